@@ -11,12 +11,23 @@ use b3_utils::{
     NanoTimeStamp,
 };
 use candid::Principal;
-use ciborium::into_writer;
-use ic_cdk::{api::call::call_with_payment, init, post_upgrade, pre_upgrade, query, update};
+use ic_cdk::{
+    api::{
+        call::call_with_payment,
+        management_canister::http_request::{HttpResponse, TransformArgs},
+    },
+    init, post_upgrade, pre_upgrade, query, update,
+};
 
 mod types;
 use types::*;
 
+mod migration;
+use migration::*;
+
+mod notify;
+use notify::*;
+
 mod store;
 use store::*;
 
@@ -24,10 +35,14 @@ mod utils;
 use utils::*;
 
 #[init]
-fn init() {
+fn init(config: Option<Config>) {
     log_caller!("init");
 
-    schedule_task(10, Task::Initialize);
+    if let Some(config) = config {
+        set_config(config).unwrap_or_else(revert);
+    }
+
+    enqueue_task(10, Task::Initialize);
 
     reschedule();
 }
@@ -36,33 +51,45 @@ fn init() {
 pub fn pre_upgrade() {
     log_cycle!("Pre_upgrade");
 
-    let ibe_key = get_ibe_encrypted_key().to_vec();
-    let sym_key = get_symmetric_encrypted_key().to_vec();
-
-    let mut states_bytes = vec![];
-
-    into_writer(&(ibe_key, sym_key), &mut states_bytes).unwrap();
+    let backup = StateBackup {
+        ibe_key: get_ibe_encrypted_key().to_vec(),
+        sym_key: get_symmetric_encrypted_key().to_vec(),
+    };
 
-    with_backup_mem_mut(|b| b.set_backup(states_bytes));
+    with_backup_mem_mut(|b| b.set_backup(encode(&backup)));
 }
 
 #[post_upgrade]
-pub fn post_upgrade() {
+pub fn post_upgrade(config: Option<Config>) {
     log_cycle!("Post_upgrade");
 
     let states_bytes = with_backup_mem(|b| b.get_backup());
 
-    let (ibe_key, sym_key) =
-        ciborium::de::from_reader(&*states_bytes).expect("failed to decode state");
+    let state_version = version_of(&states_bytes);
 
-    set_ibe_encryption_key(ibe_key);
-    set_symmetric_encryption_key(sym_key);
+    let backup: StateBackup = decode(&states_bytes).expect("failed to decode state");
+
+    set_ibe_encryption_key(backup.ibe_key);
+    set_symmetric_encryption_key(backup.sym_key);
+
+    run_migrations(state_version);
+
+    // keep the stored config unless the upgrade sets a new one
+    if let Some(config) = config {
+        set_config(config).unwrap_or_else(revert);
+    }
 
     for detail in partition_details() {
         log_cycle!("{:?}", detail);
     }
 
-    // reschedule();
+    // the global timer is cleared on upgrade, make sure the clean ups are queued and re-arm it
+    let interval = get_config().clean_up_interval_secs;
+
+    enqueue_task(interval, Task::CleanUpKeys);
+    enqueue_task(interval, Task::CleanUpAnonymousUsers);
+
+    reschedule();
 }
 
 #[query(guard = "caller_is_not_anonymous")]
@@ -76,68 +103,35 @@ fn user_data() -> UserData {
 fn user_simple_notes(public_key: Vec<u8>) -> Vec<String> {
     let public_key = vec_to_fixed_array(&public_key).unwrap_or_else(revert);
 
-    SIMPLE_NOTES
-        .with(|notes| {
-            let notes = notes.borrow();
-
-            notes.get(&public_key).cloned()
-        })
+    with_simple_notes(|notes| notes.get(&public_key))
+        .map(|notes| notes.into_inner())
         .unwrap_or(vec![])
 }
 
-#[query]
-fn user_notes(public_key: Option<Vec<u8>>) -> (NanoTimeStamp, Vec<UserText>) {
+/// Anonymous callers prove their key, so this is an update that consumes the challenge.
+#[update]
+fn user_notes(proof: Option<AnonymousProof>) -> (NanoTimeStamp, Vec<UserText>) {
     let caller = log_caller!("user_notes");
 
-    if caller == Principal::anonymous() {
-        match public_key {
-            Some(public_key) => {
-                let public_key = vec_to_fixed_array(&public_key).unwrap_or_else(revert);
-
-                with_anonymous_user_or_add(&public_key, |user| {
-                    let texts = user
-                        .iter_texts()
-                        .map(|text_id| {
-                            with_encrypted_texts(|texts| {
-                                let text = texts.get(text_id).unwrap();
-
-                                UserText {
-                                    id: text_id.to_string(),
-                                    text: text.clone(),
-                                }
-                            })
-                        })
-                        .collect();
-
-                    (user.get_created_at(), texts)
-                })
-            }
-            None => return revert("Error::public key is required for anonymous user"),
-        }
-    } else {
-        let time = NanoTimeStamp::default();
-        with_user(&caller.into(), |user| {
-            let texts = user
-                .iter_texts()
-                .map(|text_id| {
-                    with_encrypted_texts(|texts| {
-                        let text = texts.get(text_id).unwrap();
-
-                        UserText {
-                            id: text_id.to_string(),
-                            text: text.clone(),
-                        }
-                    })
-                })
-                .collect();
+    match caller_proven_owner(caller, proof, "user_notes", &[]).unwrap_or_else(revert) {
+        NoteOwner::Anonymous(public_key) => {
+            let public_key = vec_to_fixed_array(&public_key).unwrap_or_else(revert);
 
-            Ok((time.clone(), texts))
-        })
-        .unwrap_or((time, vec![]))
+            get_anonymous_user(&public_key)
+                .map(|user| (user.get_created_at(), get_user_texts(user.iter_texts())))
+                .unwrap_or((NanoTimeStamp::default(), vec![]))
+        }
+        NoteOwner::User(principal) => {
+            let time = NanoTimeStamp::default();
+            with_user(&principal.into(), |user| {
+                Ok((time.clone(), get_user_texts(user.iter_texts())))
+            })
+            .unwrap_or((time, vec![]))
+        }
     }
 }
 
-#[query]
+#[query(guard = "caller_is_admin")]
 fn anonymous_users() -> Vec<(PublicKey, AnonymousUserData)> {
     log_caller!("anonymous_users");
 
@@ -149,7 +143,7 @@ fn anonymous_users() -> Vec<(PublicKey, AnonymousUserData)> {
     })
 }
 
-#[query]
+#[query(guard = "caller_is_auditor")]
 fn anonymous_user(public_key: Vec<u8>) -> AnonymousUserData {
     log_caller!("anonymous_user");
 
@@ -158,33 +152,40 @@ fn anonymous_user(public_key: Vec<u8>) -> AnonymousUserData {
     get_anonymous_user(&public_key).unwrap_or_else(revert)
 }
 
-#[query]
+#[query(guard = "caller_is_auditor")]
 fn anonymous_user_notes(public_key: Vec<u8>) -> Vec<UserText> {
     log_caller!("anonymous_user_notes");
 
     let public_key = vec_to_fixed_array(&public_key).unwrap_or_else(revert);
 
-    with_anonymous_user(&public_key, |user| {
-        let texts = user
-            .iter_texts()
-            .map(|text_id| {
-                with_encrypted_texts(|texts| {
-                    let text = texts.get(text_id).unwrap();
-
-                    UserText {
-                        id: text_id.to_string(),
-                        text: text.clone(),
-                    }
-                })
-            })
-            .collect();
-
-        Ok(texts)
-    })
-    .unwrap_or(vec![])
+    with_anonymous_user(&public_key, |user| Ok(get_user_texts(user.iter_texts()))).unwrap_or(vec![])
 }
 
 #[query]
+fn list_notes(cursor: Option<Nonce>, limit: Option<u64>, public_key: Option<Vec<u8>>) -> NotePage {
+    let caller = log_caller!("list_notes");
+
+    let owner = caller_note_owner(caller, public_key).unwrap_or_else(revert);
+    let limit = NotePage::limit(limit);
+
+    let text_ids = match &owner {
+        NoteOwner::Anonymous(public_key) => {
+            let public_key = vec_to_fixed_array(public_key).unwrap_or_else(revert);
+
+            get_anonymous_user(&public_key)
+                .map(|user| user.iter_texts().cloned().collect())
+                .unwrap_or(vec![])
+        }
+        NoteOwner::User(principal) => with_user(&principal.clone().into(), |user| {
+            Ok(user.iter_texts().cloned().collect())
+        })
+        .unwrap_or(vec![]),
+    };
+
+    get_note_page(&owner, text_ids.iter(), cursor, limit)
+}
+
+#[query(guard = "caller_is_admin")]
 fn encrypted_texts() -> Vec<UserText> {
     log_caller!("encrypted_texts");
 
@@ -199,93 +200,249 @@ fn encrypted_texts() -> Vec<UserText> {
     })
 }
 
+#[query]
+fn anonymous_challenge(public_key: Vec<u8>, method: String, args_hash: Vec<u8>) -> Vec<u8> {
+    let public_key = vec_to_fixed_array(&public_key).unwrap_or_else(revert);
+
+    let counter = get_challenge_counter(&public_key);
+
+    AnonymousProof::challenge(&public_key, counter, &method, &args_hash)
+}
+
 #[update]
-async fn add_simple_note(public_key: Vec<u8>, note: String) -> usize {
+async fn add_simple_note(proof: AnonymousProof, note: String) -> usize {
     log_caller!("add_simple_note");
 
-    let public_key = vec_to_fixed_array(&public_key).unwrap_or_else(revert);
+    let public_key = verify_anonymous_proof(&proof, "add_simple_note", &[note.as_bytes()])
+        .unwrap_or_else(revert);
 
-    SIMPLE_NOTES.with(|notes| {
-        let mut notes = notes.borrow_mut();
+    with_simple_notes(|notes| {
+        let mut simple_notes = notes.get(&public_key).unwrap_or_default();
 
-        let notes = notes.entry(public_key).or_insert(vec![]);
+        let len = simple_notes.add_note(note).unwrap_or_else(revert);
 
-        notes.push(note);
+        notes.insert(public_key, simple_notes);
 
-        notes.len()
+        len
     })
 }
 
 #[update]
-async fn save_encrypted_text(encrypted_text: Vec<u8>, public_key: Option<Vec<u8>>) -> Nonce {
+async fn save_encrypted_text(
+    encrypted_text: Vec<u8>,
+    proof: Option<AnonymousProof>,
+    scheme: Option<EncryptionScheme>,
+) -> Nonce {
     let caller = log_caller!("save_encrypted_text");
-    // public key for anonymous users is required
-    if caller == Principal::anonymous() && public_key.is_none() {
-        return revert("Error::public key is required for anonymous user!");
-    }
+    // proof of the public key for anonymous users is required
+    let owner = caller_proven_owner(caller, proof, "save_encrypted_text", &[&encrypted_text])
+        .unwrap_or_else(revert);
 
     let text_id = increment_nonce().unwrap_or_else(revert);
 
+    with_note_metas(|metas| {
+        metas.insert(
+            text_id.clone(),
+            NoteMeta::new(&owner, encrypted_text.len() as u64, scheme),
+        );
+    });
+
     with_encrypted_texts(|texts| {
-        texts.insert(text_id, EncryptedText::new(encrypted_text));
+        texts.insert(text_id.clone(), EncryptedText::new(encrypted_text));
     });
 
-    if caller == Principal::anonymous() {
-        // this is safe because we checked for None above
-        let public_key = vec_to_fixed_array(&public_key.unwrap()).unwrap_or_else(revert);
+    add_text_id_to_owner(&owner, text_id.clone()).unwrap_or_else(revert);
 
-        log_cycle!("Adding text id to anonymous user!");
-        with_anonymous_user_or_add(&public_key, |user| {
-            user.add_text_id(text_id.clone()).unwrap_or_else(revert);
+    text_id
+}
 
-            text_id
-        })
-    } else {
-        with_user_or_add(&caller.into(), |user| {
-            user.add_text_id(text_id.clone()).unwrap_or_else(revert);
+#[update]
+fn edit_encrypted_text(
+    text_id: Nonce,
+    encrypted_text: Vec<u8>,
+    proof: Option<AnonymousProof>,
+    scheme: Option<EncryptionScheme>,
+) {
+    let caller = log_caller!("edit_encrypted_text");
 
-            text_id
-        })
+    let owner = caller_proven_owner(
+        caller,
+        proof,
+        "edit_encrypted_text",
+        &[&text_id.to_le_bytes(), &encrypted_text],
+    )
+    .unwrap_or_else(revert);
+
+    if !owner_has_text_id(&owner, &text_id).unwrap_or_else(revert) {
+        return revert("Error::User does not own the text_id!");
     }
+
+    let size = encrypted_text.len() as u64;
+
+    remove_chunked_text(&text_id);
+
+    with_encrypted_texts(|texts| {
+        texts.insert(text_id.clone(), EncryptedText::new(encrypted_text));
+    });
+
+    with_note_meta(&text_id, |meta| meta.update(size, scheme));
 }
 
 #[update]
-fn edit_encrypted_text(text_id: Nonce, encrypted_text: Vec<u8>, public_key: Option<Vec<u8>>) {
-    let caller = log_caller!("edit_encrypted_text");
+fn delete_encrypted_text(text_id: Nonce, proof: Option<AnonymousProof>) {
+    let caller = log_caller!("delete_encrypted_text");
+
+    let owner = caller_proven_owner(
+        caller,
+        proof,
+        "delete_encrypted_text",
+        &[&text_id.to_le_bytes()],
+    )
+    .unwrap_or_else(revert);
+
+    if !owner_has_text_id(&owner, &text_id).unwrap_or_else(revert) {
+        return revert("Error::User does not own the text_id!");
+    }
+
+    remove_text_id_from_owner(&owner, &text_id).unwrap_or_else(revert);
+
+    delete_note(&text_id);
+}
 
-    if caller == Principal::anonymous() {
-        match public_key {
-            Some(public_key) => {
-                let public_key = vec_to_fixed_array(&public_key).unwrap_or_else(revert);
+#[update]
+fn begin_encrypted_text_upload(total_size: u64, proof: Option<AnonymousProof>) -> Nonce {
+    let caller = log_caller!("begin_encrypted_text_upload");
 
-                with_anonymous_user(&public_key, |data| {
-                    data.remove_text_id(&text_id).unwrap_or_else(revert);
+    let owner = caller_proven_owner(
+        caller,
+        proof,
+        "begin_encrypted_text_upload",
+        &[&total_size.to_be_bytes()],
+    )
+    .unwrap_or_else(revert);
 
-                    with_encrypted_texts(|texts| {
-                        texts.insert(text_id.clone(), EncryptedText::new(encrypted_text));
-                    });
+    // fail early instead of after the whole upload
+    can_add_text_id(&owner).unwrap_or_else(revert);
 
-                    data.add_text_id(text_id).unwrap_or_else(revert);
+    let chunked_text = ChunkedText::new(owner, total_size).unwrap_or_else(revert);
 
-                    Ok(())
-                })
-                .unwrap_or_else(revert)
-            }
-            None => revert("Error::public key is required!"),
+    let text_id = increment_nonce().unwrap_or_else(revert);
+
+    with_chunked_texts(|uploads| {
+        uploads.insert(text_id.clone(), chunked_text);
+    });
+
+    text_id
+}
+
+#[update]
+fn append_encrypted_text_chunk(
+    text_id: Nonce,
+    chunk: Vec<u8>,
+    proof: Option<AnonymousProof>,
+) -> u32 {
+    let caller = log_caller!("append_encrypted_text_chunk");
+
+    let owner = caller_proven_owner(
+        caller,
+        proof,
+        "append_encrypted_text_chunk",
+        &[&text_id.to_le_bytes(), &chunk],
+    )
+    .unwrap_or_else(revert);
+
+    let chunk = EncryptedChunk::new(chunk).unwrap_or_else(revert);
+
+    let index = with_chunked_text(&text_id, |upload| {
+        if !upload.is_owned_by(&owner) {
+            return Err("Error::User does not own the upload!".to_string());
         }
-    } else {
-        with_user(&caller.into(), |user| {
-            user.remove_text_id(&text_id).unwrap_or_else(revert);
 
-            with_encrypted_texts(|texts| {
-                texts.insert(text_id.clone(), EncryptedText::new(encrypted_text));
-            });
+        upload.append(chunk.len())
+    })
+    .unwrap_or_else(revert);
 
-            user.add_text_id(text_id).unwrap_or_else(revert);
+    with_encrypted_chunks(|chunks| {
+        chunks.insert(ChunkKey::new(text_id, index), chunk);
+    });
 
-            Ok(())
-        })
-        .unwrap_or_else(revert)
+    index
+}
+
+#[update]
+fn commit_encrypted_text_upload(
+    text_id: Nonce,
+    proof: Option<AnonymousProof>,
+    scheme: Option<EncryptionScheme>,
+) {
+    let caller = log_caller!("commit_encrypted_text_upload");
+
+    let owner = caller_proven_owner(
+        caller,
+        proof,
+        "commit_encrypted_text_upload",
+        &[&text_id.to_le_bytes()],
+    )
+    .unwrap_or_else(revert);
+
+    let size = with_chunked_text(&text_id, |upload| {
+        if !upload.is_owned_by(&owner) {
+            return Err("Error::User does not own the upload!".to_string());
+        }
+
+        upload.commit()?;
+
+        Ok(upload.size())
+    })
+    .unwrap_or_else(revert);
+
+    with_note_metas(|metas| {
+        metas.insert(text_id.clone(), NoteMeta::new_chunked(&owner, size, scheme));
+    });
+
+    add_text_id_to_owner(&owner, text_id).unwrap_or_else(revert);
+}
+
+/// Streams a chunked note to its owner or one of its recipients, anonymous
+/// callers prove their key for every chunk.
+#[query]
+fn get_encrypted_text_chunk(
+    text_id: Nonce,
+    index: u32,
+    proof: Option<AnonymousProof>,
+) -> EncryptedTextChunk {
+    let caller = log_caller!("get_encrypted_text_chunk");
+
+    let reader = caller_proven_owner(
+        caller,
+        proof,
+        "get_encrypted_text_chunk",
+        &[&text_id.to_le_bytes(), &index.to_le_bytes()],
+    )
+    .unwrap_or_else(revert);
+
+    if !can_read_note(&reader, &text_id) {
+        return revert("Error::Caller can't read the text_id!");
+    }
+
+    let chunked_text = with_chunked_texts(|texts| texts.get(&text_id))
+        .filter(|text| text.is_committed())
+        .ok_or("Error::Text not found!".to_string())
+        .unwrap_or_else(revert);
+
+    let chunk = with_encrypted_chunks(|chunks| chunks.get(&ChunkKey::new(text_id, index)))
+        .ok_or("Error::Chunk not found!".to_string())
+        .unwrap_or_else(revert);
+
+    let next = index + 1;
+
+    EncryptedTextChunk {
+        chunk: chunk.into_inner(),
+        next: if next < chunked_text.chunks() {
+            Some(next)
+        } else {
+            None
+        },
     }
 }
 
@@ -303,22 +460,80 @@ fn get_one_time_key_details(text_id: Nonce) -> OneTimeKey {
     with_one_time_key(&text_id, |key| Ok(key.clone())).unwrap_or_else(revert)
 }
 
+/// Shares a note through a one time key.
+///
+/// Anonymous owners share with their own public key, authenticated users pass
+/// the public key of a fresh link key pair. Either way the reader only gets the
+/// key for `share_derivation_id`, so the note's content key is passed wrapped
+/// to that id, or the note itself is encrypted to it.
 #[update]
-fn set_one_time_key(text_id: Nonce, public_key: Vec<u8>) {
-    log_caller!("set_one_time_key");
+fn set_one_time_key(
+    text_id: Nonce,
+    proof: Option<AnonymousProof>,
+    public_key: Option<Vec<u8>>,
+    wrapped_key: Option<Vec<u8>>,
+) {
+    let caller = log_caller!("set_one_time_key");
+
+    let owner = caller_proven_owner(
+        caller,
+        proof,
+        "set_one_time_key",
+        &[
+            &text_id.to_le_bytes(),
+            &wrapped_key.clone().unwrap_or_default(),
+        ],
+    )
+    .unwrap_or_else(revert);
+
+    if !owner_has_text_id(&owner, &text_id).unwrap_or_else(revert) {
+        return revert("Error::User does not own the text_id!");
+    }
 
-    let public_key = vec_to_fixed_array(&public_key).unwrap_or_else(revert);
+    // a one time read returns the whole note in one reply
+    if is_chunked_text(&text_id) {
+        return revert("Error::Chunked notes can't be shared with a one time key!");
+    }
 
-    // check if user own the text_id want to share
-    let user_data = get_anonymous_user(&public_key).unwrap_or_else(revert);
+    let one_time_key = match (owner, public_key) {
+        (NoteOwner::Anonymous(owner_public_key), None) => OneTimeKey::new(
+            vec_to_fixed_array(&owner_public_key).unwrap_or_else(revert),
+            NanoTimeStamp::now(),
+        ),
+        (NoteOwner::Anonymous(_), Some(_)) => {
+            return revert("Error::Anonymous users share with their own public key!")
+        }
+        (NoteOwner::User(principal), Some(link_public_key)) => OneTimeKey::new_for_user(
+            principal,
+            vec_to_fixed_array(&link_public_key).unwrap_or_else(revert),
+            NanoTimeStamp::now(),
+        ),
+        (NoteOwner::User(_), None) => {
+            return revert("Error::Link public key is required for authenticated users!")
+        }
+    };
 
-    if !user_data.has_text_id(&text_id) {
-        return revert("Error::User does not own the text_id!");
-    }
+    let wrapped_key = wrapped_key
+        .map(WrappedKey::new)
+        .transpose()
+        .unwrap_or_else(revert);
+
+    with_note_meta(&text_id, |meta| meta.set_share_status(ShareStatus::Shared));
 
     with_one_time_keys(|keys| {
-        keys.insert(text_id, OneTimeKey::new(public_key));
+        keys.insert(text_id.clone(), one_time_key);
     });
+
+    with_one_time_wrapped_keys(|wrapped_keys| match wrapped_key {
+        Some(wrapped_key) => wrapped_keys.insert(text_id, wrapped_key),
+        None => wrapped_keys.remove(&text_id),
+    });
+}
+
+/// The derivation id a note is shared under with the one time key or link key `public_key`.
+#[query]
+fn share_derivation_id(text_id: Nonce, public_key: Vec<u8>) -> Vec<u8> {
+    utils::share_derivation_id(&text_id, &public_key)
 }
 
 #[update]
@@ -326,19 +541,18 @@ async fn read_with_one_time_key(
     text_id: Nonce,
     signature: Vec<u8>,
     reader_public_key: Vec<u8>,
-) -> Result<(Vec<u8>, Vec<u8>), String> {
+) -> Result<SharedNote, String> {
     let caller = log_caller!("read_with_one_time_key");
 
-    let one_time_key =
-        with_one_time_key_and_try(&text_id, |key| Ok(key.clone())).unwrap_or_else(revert);
-
-    if one_time_key.out_of_tries() {
-        return report("Error::One time key is out of tries!");
-    }
+    let reader = AccessEntry::reader(caller, reader_public_key.clone());
+    let record_access = |outcome| {
+        record_note_access(
+            &text_id,
+            AccessEntry::for_reader(reader.clone(), outcome, NanoTimeStamp::now()),
+        )
+    };
 
-    if one_time_key.is_expired() {
-        return report("Error::One time key is expired!");
-    }
+    let one_time_key = try_one_time_key(&text_id, reader.clone(), NanoTimeStamp::now())?;
 
     let verified = verify_pairing(
         &one_time_key.public_key(),
@@ -347,22 +561,454 @@ async fn read_with_one_time_key(
     );
 
     match verified {
-        Ok(_) => {
+        Ok(true) => {
+            // scoped to the note and key it was shared with, anonymous owners
+            // share with their own public key and must never hand out its key
+            let derivation_id = utils::share_derivation_id(&text_id, one_time_key.public_key());
+
+            let encrypted_key = VetKDManagement(ic_cdk::id())
+                .request_encrypted_key(
+                    derivation_id,
+                    vec![b"ibe_encryption".to_vec()],
+                    reader_public_key,
+                )
+                .await
+                .unwrap_or_else(revert);
+
+            // another read may have used the key while this one was waiting
+            let (one_time_key, wrapped_key) = take_one_time_key(&text_id)?;
+
             let encrypted_text =
                 with_encrypted_text(&text_id, |text| Ok(text.clone())).unwrap_or_else(revert);
 
-            let encrypted_key = VetKD::new(caller.into())
-                .request_encrypted_key(vec![b"ibe_encryption".to_vec()], reader_public_key)
-                .await
+            record_access(AccessOutcome::Success);
+
+            finish_shared_read(one_time_key.owner(), &text_id, NanoTimeStamp::now());
+
+            Ok(SharedNote {
+                encrypted_text,
+                encrypted_key,
+                wrapped_key,
+            })
+        }
+        _ => {
+            record_access(AccessOutcome::BadSignature);
+
+            report("Error::Invalid signature!")
+        }
+    }
+}
+
+#[update]
+fn set_burn_after_reading(text_id: Nonce, enabled: bool, proof: Option<AnonymousProof>) {
+    let caller = log_caller!("set_burn_after_reading");
+
+    let owner = caller_proven_owner(
+        caller,
+        proof,
+        "set_burn_after_reading",
+        &[&text_id.to_le_bytes(), &[enabled as u8]],
+    )
+    .unwrap_or_else(revert);
+
+    if !owner_has_text_id(&owner, &text_id).unwrap_or_else(revert) {
+        return revert("Error::User does not own the text_id!");
+    }
+
+    with_note_metas(|metas| {
+        let mut meta = metas.get(&text_id).unwrap_or_else(|| {
+            let size = with_encrypted_texts(|texts| texts.get(&text_id))
+                .map_or(0, |text| text.clone().len() as u64);
+
+            NoteMeta::untracked(&owner, size)
+        });
+
+        meta.set_burn_after_reading(enabled);
+
+        metas.insert(text_id, meta);
+    });
+}
+
+#[query]
+fn note_access_log(text_id: Nonce, public_key: Option<Vec<u8>>) -> Vec<AccessEntry> {
+    let caller = log_caller!("note_access_log");
+
+    let owner = caller_note_owner(caller, public_key).unwrap_or_else(revert);
+
+    if !owner_has_text_id(&owner, &text_id).unwrap_or_else(revert) {
+        return revert("Error::User does not own the text_id!");
+    }
+
+    with_note_access_logs(|logs| logs.get(&text_id))
+        .map(|log| log.into_inner())
+        .unwrap_or(vec![])
+}
+
+#[query]
+fn note_tombstones(public_key: Option<Vec<u8>>) -> Vec<(Nonce, NoteTombstone)> {
+    let caller = log_caller!("note_tombstones");
+
+    let owner = caller_note_owner(caller, public_key).unwrap_or_else(revert);
+
+    get_owner_tombstones(&owner)
+}
+
+/// Moves the notes of an anonymous session into the caller's account.
+///
+/// `signature` is an `AnonymousProof` signature over the caller's principal.
+/// The notes stay encrypted to the anonymous identity until the client
+/// re-encrypts them with `edit_encrypted_text`, with the key from
+/// `claimed_identity_encrypted_key`. One time keys and share links of the
+/// anonymous session are revoked.
+#[update(guard = "caller_is_not_anonymous")]
+fn claim_anonymous_account(public_key: Vec<u8>, signature: Vec<u8>) -> Vec<Nonce> {
+    let caller = log_caller!("claim_anonymous_account");
+
+    let proof = AnonymousProof {
+        public_key,
+        signature,
+    };
+
+    let public_key =
+        verify_anonymous_proof(&proof, "claim_anonymous_account", &[caller.as_slice()])
+            .unwrap_or_else(revert);
+
+    claim_anonymous_notes(&public_key, caller).unwrap_or_else(revert)
+}
+
+/// The key of an anonymous identity the caller claimed, for the notes still encrypted to it.
+#[update(guard = "caller_is_not_anonymous")]
+async fn claimed_identity_encrypted_key(
+    public_key: Vec<u8>,
+    scheme: KeyScheme,
+    encryption_public_key: Vec<u8>,
+) -> Vec<u8> {
+    let caller = log_caller!("claimed_identity_encrypted_key");
+
+    let public_key: PublicKey = vec_to_fixed_array(&public_key).unwrap_or_else(revert);
+
+    if !has_claimed_identity(&public_key, caller) {
+        return revert("Error::Anonymous identity was not claimed by the caller!");
+    }
+
+    // the derivation id anonymous callers get their own keys under
+    VetKDManagement(ic_cdk::id())
+        .request_encrypted_key(
+            caller_derivation_id(Principal::anonymous(), &public_key),
+            scheme.derivation_path(),
+            encryption_public_key,
+        )
+        .await
+        .unwrap_or_else(revert)
+}
+
+#[update]
+fn create_share_link(text_id: Nonce, args: ShareLinkArgs, proof: Option<AnonymousProof>) -> Nonce {
+    let caller = log_caller!("create_share_link");
+
+    let text_id_bytes = text_id.to_le_bytes();
+    let proof_args = args.proof_args();
+
+    let signed_args: Vec<&[u8]> = std::iter::once(&text_id_bytes[..])
+        .chain(proof_args.iter().map(|arg| arg.as_slice()))
+        .collect();
+
+    let owner = caller_proven_owner(caller, proof, "create_share_link", &signed_args)
+        .unwrap_or_else(revert);
+
+    if !owner_has_text_id(&owner, &text_id).unwrap_or_else(revert) {
+        return revert("Error::User does not own the text_id!");
+    }
+
+    // a link read returns the whole note in one reply, recipients stream chunked notes instead
+    if is_chunked_text(&text_id) {
+        return revert(
+            "Error::Chunked notes can't be shared with a link, add a recipient instead!",
+        );
+    }
+
+    let share_link =
+        ShareLink::new(text_id.clone(), owner, args, NanoTimeStamp::now()).unwrap_or_else(revert);
+
+    let link_id = insert_share_link(share_link).unwrap_or_else(revert);
+
+    refresh_share_status(&text_id);
+
+    link_id
+}
+
+#[query]
+fn list_share_links(text_id: Nonce, public_key: Option<Vec<u8>>) -> Vec<(Nonce, ShareLink)> {
+    let caller = log_caller!("list_share_links");
+
+    let owner = caller_note_owner(caller, public_key).unwrap_or_else(revert);
+
+    if !owner_has_text_id(&owner, &text_id).unwrap_or_else(revert) {
+        return revert("Error::User does not own the text_id!");
+    }
+
+    get_note_share_links(&text_id)
+}
+
+#[update]
+fn revoke_share_link(link_id: Nonce, proof: Option<AnonymousProof>) {
+    let caller = log_caller!("revoke_share_link");
+
+    let owner = caller_proven_owner(
+        caller,
+        proof,
+        "revoke_share_link",
+        &[&link_id.to_le_bytes()],
+    )
+    .unwrap_or_else(revert);
+
+    let share_link = with_share_links(|share_links| share_links.get(&link_id))
+        .ok_or("Error::Share link not found!".to_string())
+        .unwrap_or_else(revert);
+
+    if share_link.owner() != &owner {
+        return revert("Error::User does not own the share link!");
+    }
+
+    with_share_links(|share_links| share_links.remove(&link_id));
+
+    refresh_share_status(share_link.text_id());
+}
+
+/// Opens a share link with a signature of its id from the link's key pair.
+///
+/// The returned key is for the link's `share_derivation_id`, it opens the
+/// note itself when the note was encrypted to it, or the `wrapped_key` otherwise.
+#[update]
+async fn read_share_link(
+    link_id: Nonce,
+    signature: Vec<u8>,
+    reader_public_key: Vec<u8>,
+) -> Result<SharedNote, String> {
+    let caller = log_caller!("read_share_link");
+
+    let share_link = with_share_links(|share_links| share_links.get(&link_id))
+        .ok_or("Error::Share link not found!".to_string())
+        .unwrap_or_else(revert);
+
+    let text_id = share_link.text_id().clone();
+
+    let reader_key = reader_public_key.clone();
+    let record_access = |outcome| {
+        record_note_access(
+            &text_id,
+            AccessEntry::new(caller, reader_key.clone(), outcome, NanoTimeStamp::now()),
+        )
+    };
+
+    if share_link.out_of_tries() {
+        record_access(AccessOutcome::OutOfTries);
+
+        return report("Error::Share link is out of tries!");
+    }
+
+    if share_link.is_expired() {
+        record_access(AccessOutcome::Expired);
+
+        return report("Error::Share link is expired!");
+    }
+
+    let verified = verify_pairing(share_link.public_key(), &signature, &link_id.to_le_bytes());
+
+    if !matches!(verified, Ok(true)) {
+        with_share_link(&link_id, |share_link| share_link.add_try()).unwrap_or_else(revert);
+
+        record_access(AccessOutcome::BadSignature);
+
+        return report("Error::Invalid signature!");
+    }
+
+    let derivation_id = utils::share_derivation_id(&text_id, share_link.public_key());
+
+    let encrypted_key = VetKDManagement(ic_cdk::id())
+        .request_encrypted_key(
+            derivation_id,
+            vec![b"ibe_encryption".to_vec()],
+            reader_public_key,
+        )
+        .await
+        .unwrap_or_else(revert);
+
+    // the link may have been revoked or used up while this read was waiting
+    let used_up = match with_share_link(&link_id, |share_link| share_link.add_read()) {
+        Ok(used_up) => used_up,
+        Err(_) => return report("Error::Share link was revoked or already used!"),
+    };
+
+    if used_up {
+        with_share_links(|share_links| share_links.remove(&link_id));
+    }
+
+    let encrypted_text =
+        with_encrypted_text(&text_id, |text| Ok(text.clone())).unwrap_or_else(revert);
+
+    record_access(AccessOutcome::Success);
+
+    finish_shared_read(share_link.owner().clone(), &text_id, NanoTimeStamp::now());
+
+    Ok(SharedNote {
+        encrypted_text,
+        encrypted_key,
+        wrapped_key: share_link.wrapped_key(),
+    })
+}
+
+#[update]
+fn add_recipient(
+    text_id: Nonce,
+    recipient: RecipientIdentity,
+    wrapped_key: Vec<u8>,
+    proof: Option<AnonymousProof>,
+) {
+    let caller = log_caller!("add_recipient");
+
+    let owner = caller_proven_owner(
+        caller,
+        proof,
+        "add_recipient",
+        &[
+            &text_id.to_le_bytes(),
+            &recipient.derivation_id(),
+            &wrapped_key,
+        ],
+    )
+    .unwrap_or_else(revert);
+
+    if !owner_has_text_id(&owner, &text_id).unwrap_or_else(revert) {
+        return revert("Error::User does not own the text_id!");
+    }
+
+    with_note_recipients(|recipients| {
+        let mut note_recipients = recipients.get(&text_id).unwrap_or_default();
+
+        note_recipients
+            .add(recipient, wrapped_key, NanoTimeStamp::now())
+            .unwrap_or_else(revert);
+
+        recipients.insert(text_id.clone(), note_recipients);
+    });
+
+    refresh_share_status(&text_id);
+}
+
+#[update]
+fn remove_recipient(text_id: Nonce, recipient: RecipientIdentity, proof: Option<AnonymousProof>) {
+    let caller = log_caller!("remove_recipient");
+
+    let owner = caller_proven_owner(
+        caller,
+        proof,
+        "remove_recipient",
+        &[&text_id.to_le_bytes(), &recipient.derivation_id()],
+    )
+    .unwrap_or_else(revert);
+
+    if !owner_has_text_id(&owner, &text_id).unwrap_or_else(revert) {
+        return revert("Error::User does not own the text_id!");
+    }
+
+    with_note_recipients(|recipients| {
+        let mut note_recipients = recipients
+            .get(&text_id)
+            .ok_or("Error::Recipient not found!".to_string())
+            .unwrap_or_else(revert);
+
+        note_recipients.remove(&recipient).unwrap_or_else(revert);
+
+        if note_recipients.is_empty() {
+            recipients.remove(&text_id);
+        } else {
+            recipients.insert(text_id.clone(), note_recipients);
+        }
+    });
+
+    refresh_share_status(&text_id);
+}
+
+#[query]
+fn note_recipients(text_id: Nonce, public_key: Option<Vec<u8>>) -> Vec<RecipientIdentity> {
+    let caller = log_caller!("note_recipients");
+
+    let owner = caller_note_owner(caller, public_key).unwrap_or_else(revert);
+
+    if !owner_has_text_id(&owner, &text_id).unwrap_or_else(revert) {
+        return revert("Error::User does not own the text_id!");
+    }
+
+    with_note_recipients(|recipients| recipients.get(&text_id))
+        .map(|recipients| recipients.identities())
+        .unwrap_or(vec![])
+}
+
+/// Returns the recipient's wrapped content key together with the vetKD key
+/// for its identity, encrypted to `encryption_public_key`.
+#[update]
+async fn recipient_encrypted_key(
+    text_id: Nonce,
+    recipient: RecipientIdentity,
+    encryption_public_key: Vec<u8>,
+    proof: Option<AnonymousProof>,
+) -> RecipientKey {
+    let caller = log_caller!("recipient_encrypted_key");
+
+    match &recipient {
+        RecipientIdentity::Principal(principal) => {
+            if caller != *principal {
+                return revert("Error::Caller is not the recipient!");
+            }
+        }
+        RecipientIdentity::PublicKey(public_key) => {
+            let proof = proof
+                .ok_or("Error::Ownership proof is required for anonymous user!".to_string())
                 .unwrap_or_else(revert);
 
-            with_one_time_keys(|keys| {
-                keys.remove(&text_id).unwrap();
-            });
+            if proof.public_key != *public_key {
+                return revert("Error::Proof is not for the recipient public key!");
+            }
 
-            Ok((encrypted_text, encrypted_key))
+            verify_anonymous_proof(
+                &proof,
+                "recipient_encrypted_key",
+                &[&text_id.to_le_bytes(), &encryption_public_key],
+            )
+            .unwrap_or_else(revert);
         }
-        Err(_) => report("Error::Invalid signature!"),
+    }
+
+    let wrapped_key = with_note_recipients(|recipients| {
+        recipients
+            .get(&text_id)
+            .and_then(|recipients| recipients.get(&recipient).map(|r| r.wrapped_key().to_vec()))
+    })
+    .ok_or("Error::Recipient not found!".to_string())
+    .unwrap_or_else(revert);
+
+    let encrypted_key = VetKDManagement(ic_cdk::id())
+        .request_encrypted_key(
+            recipient.derivation_id(),
+            vec![b"ibe_encryption".to_vec()],
+            encryption_public_key,
+        )
+        .await
+        .unwrap_or_else(revert);
+
+    record_note_access(
+        &text_id,
+        AccessEntry::for_reader(
+            recipient.clone(),
+            AccessOutcome::Success,
+            NanoTimeStamp::now(),
+        ),
+    );
+
+    RecipientKey {
+        wrapped_key,
+        encrypted_key,
     }
 }
 
@@ -376,6 +1022,32 @@ async fn symmetric_key_verification_key() -> Vec<u8> {
     get_symmetric_encrypted_key().to_vec()
 }
 
+#[update]
+async fn timelock_public_key() -> Vec<u8> {
+    log_caller!("timelock_public_key");
+
+    VetKDManagement(ic_cdk::id())
+        .request_public_key(vec![b"timelock".to_vec()])
+        .await
+        .unwrap_or_else(revert)
+}
+
+#[update]
+async fn timelock_encrypted_key(epoch: u64, encryption_public_key: Vec<u8>) -> Vec<u8> {
+    log_caller!(format!("timelock_encrypted_key: {}", epoch));
+
+    check_timelock(epoch, NanoTimeStamp::now().to_secs()).unwrap_or_else(revert);
+
+    VetKDManagement(ic_cdk::id())
+        .request_encrypted_key(
+            timelock_derivation_id(epoch),
+            vec![b"timelock".to_vec()],
+            encryption_public_key,
+        )
+        .await
+        .unwrap_or_else(revert)
+}
+
 #[update(guard = "caller_is_not_anonymous")]
 async fn two_factor_verification_key() -> String {
     log_caller!("two_factor_verification_key");
@@ -407,70 +1079,59 @@ async fn request_two_factor_authentication(encryption_public_key: Vec<u8>) -> St
 async fn encrypted_ibe_decryption_key_for_caller(encryption_public_key: Vec<u8>) -> Vec<u8> {
     let caller = log_caller!("encrypted_ibe_decryption_key_for_caller");
 
-    let public_key = vec_to_fixed_array(&encryption_public_key).unwrap_or_else(revert);
-
-    // check for cached key
-    if let Ok(user_data) = get_anonymous_user(&public_key) {
-        if let Some(decryption_key) = user_data.get_decryption_key().ok() {
-            return decryption_key;
-        }
-    }
-
-    // request key from VetKD Api
-    let encrypted_key = VetKD::new(caller.into())
-        .request_encrypted_key(vec![b"ibe_encryption".to_vec()], encryption_public_key)
-        .await
-        .unwrap_or_else(revert);
-
-    // cache key
-    with_anonymous_user_or_add(&public_key, |user| {
-        user.set_decryption_key(encrypted_key.clone());
-    });
-
-    encrypted_key
+    encrypted_key_for_caller(caller, KeyScheme::Ibe, encryption_public_key).await
 }
 
 #[update]
 async fn encrypted_symmetric_key_for_caller(encryption_public_key: Vec<u8>) -> Vec<u8> {
     let caller = log_caller!("encrypted_symmetric_key_for_caller");
 
+    encrypted_key_for_caller(caller, KeyScheme::Symmetric, encryption_public_key).await
+}
+
+async fn encrypted_key_for_caller(
+    caller: Principal,
+    scheme: KeyScheme,
+    encryption_public_key: Vec<u8>,
+) -> Vec<u8> {
     let public_key = vec_to_fixed_array(&encryption_public_key).unwrap_or_else(revert);
 
+    let derivation_id = caller_derivation_id(caller, &public_key);
+
+    let cache_id = key_cache_id(scheme, &derivation_id, &encryption_public_key);
+
     // check for cached key
-    if let Ok(user_data) = get_anonymous_user(&public_key) {
-        if let Some(decryption_key) = user_data.get_decryption_key().ok() {
-            return decryption_key;
-        }
+    if let Some(encrypted_key) = get_cached_key(&cache_id, scheme, &NanoTimeStamp::now()) {
+        return encrypted_key;
     }
 
     // request key from VetKD Api
-    let encrypted_key = VetKD::new(caller.into())
-        .request_encrypted_key(vec![b"symmetric_key".to_vec()], encryption_public_key)
+    let encrypted_key = VetKDManagement(ic_cdk::id())
+        .request_encrypted_key(
+            derivation_id,
+            scheme.derivation_path(),
+            encryption_public_key,
+        )
         .await
         .unwrap_or_else(revert);
 
     // cache key
-    if caller == Principal::anonymous() {
-        with_anonymous_user_or_add(&public_key, |user| {
-            user.set_decryption_key(encrypted_key.clone());
-        });
-    } else {
-        with_users(|keys| {
-            let user_data = UserData::new(encrypted_key.clone(), None);
-
-            keys.insert(caller.into(), user_data);
-        });
-    }
+    with_key_cache(|key_cache| {
+        key_cache.insert(
+            cache_id,
+            CachedKey::new(scheme, encrypted_key.clone(), NanoTimeStamp::now()),
+        );
+    });
 
     encrypted_key
 }
 
-#[query]
+#[query(guard = "caller_is_auditor")]
 fn print_log_entries() -> Vec<LogEntry> {
     export_log()
 }
 
-#[query]
+#[query(guard = "caller_is_auditor")]
 fn print_log_entries_page(page: usize, page_size: Option<usize>) -> Vec<String> {
     export_log_messages_page(page, page_size)
 }
@@ -480,15 +1141,82 @@ fn partition_details() -> Vec<PartitionDetail> {
     with_stable_mem(|p| p.partition_details())
 }
 
-#[query]
-fn timers() -> Vec<TaskTimerEntry<Task>> {
-    TASK_TIMER.with(|s| {
+#[query(guard = "caller_is_auditor")]
+fn timers() -> Vec<(Nonce, TaskTimerEntry<Task>)> {
+    let timers = TASK_TIMER.with(|s| {
         let state = s.borrow();
 
         state.get_timers()
+    });
+
+    with_tasks(|tasks| {
+        timers
+            .into_iter()
+            .filter_map(|TaskTimerEntry { task: id, time }| {
+                tasks
+                    .get(&id)
+                    .filter(|scheduled| scheduled.is_due_at(&time))
+                    .map(|scheduled| {
+                        let task = scheduled.task().clone();
+
+                        (id, TaskTimerEntry { task, time })
+                    })
+            })
+            .collect()
     })
 }
 
+#[update(guard = "caller_is_controller")]
+fn schedule_task(after_sec: u64, task: Task) -> Nonce {
+    log_caller!(format!(
+        "schedule_task: {:?} after {} secs",
+        task, after_sec
+    ));
+
+    enqueue_task(after_sec, task)
+}
+
+#[update(guard = "caller_is_controller")]
+fn cancel_task(id: Nonce) {
+    log_caller!(format!("cancel_task: {:?}", id));
+
+    remove_task(&id).unwrap_or_else(revert);
+}
+
+#[update(guard = "caller_is_controller")]
+fn run_task_now(id: Nonce) {
+    log_caller!(format!("run_task_now: {:?}", id));
+
+    requeue_task(0, id);
+}
+
+#[query(guard = "caller_is_admin")]
+fn roles() -> Vec<RoleGrant> {
+    with_roles(|roles| roles.iter().map(|(_, grant)| grant).collect())
+}
+
+#[update(guard = "caller_is_controller")]
+fn grant_role(principal: Principal, role: Role) {
+    let caller = log_caller!(format!("grant_role: {:?} to {}", role, principal.to_text()));
+
+    if principal == Principal::anonymous() {
+        return revert("Error::Cannot grant a role to the anonymous principal!");
+    }
+
+    with_roles(|roles| {
+        roles.insert(principal.into(), RoleGrant::new(principal, role, caller));
+    });
+}
+
+#[update(guard = "caller_is_controller")]
+fn revoke_role(principal: Principal) {
+    log_caller!(format!("revoke_role: {}", principal.to_text()));
+
+    with_roles(|roles| roles.remove(&principal.into()))
+        .ok_or("Error::Principal has no role!".to_string())
+        .unwrap_or_else(revert);
+}
+
 #[update(guard = "caller_is_controller")]
 async fn transfer_cycle(canister_id: Principal, amount: u64) {
     log_cycle!("Send cycle");
@@ -501,20 +1229,107 @@ async fn transfer_cycle(canister_id: Principal, amount: u64) {
     }
 }
 
+#[query]
+fn config() -> Config {
+    get_config()
+}
+
+#[update(guard = "caller_is_controller")]
+fn update_config(config: Config) {
+    log_caller!(format!("update_config: {:?}", config));
+
+    set_config(config).unwrap_or_else(revert);
+}
+
+#[query(guard = "caller_is_admin")]
+fn notification_provider() -> NotificationProvider {
+    get_notification_provider().redacted()
+}
+
+#[update(guard = "caller_is_controller")]
+fn set_notification_provider(provider: NotificationProvider) {
+    log_caller!(format!("set_notification_provider: {}", provider.endpoint));
+
+    store::set_notification_provider(provider).unwrap_or_else(revert);
+}
+
+#[update(guard = "caller_is_admin")]
+fn send_notification(message: NotificationMessage) -> Nonce {
+    log_caller!("send_notification");
+
+    let id = queue_notification(message).unwrap_or_else(revert);
+
+    enqueue_task(0, Task::Notify(id.clone()));
+
+    reschedule();
+
+    id
+}
+
+#[query(guard = "caller_is_admin")]
+fn notification(id: Nonce) -> Notification {
+    with_notifications(|notifications| notifications.get(&id))
+        .ok_or("Error::Notification not found!".to_string())
+        .unwrap_or_else(revert)
+}
+
+#[query]
+fn transform_notification(raw: TransformArgs) -> HttpResponse {
+    transform_response(raw.response)
+}
+
+#[query(guard = "caller_is_controller")]
+fn dead_tasks() -> Vec<(u64, DeadTask)> {
+    with_dead_tasks(|dead_tasks| dead_tasks.iter().collect())
+}
+
+#[update(guard = "caller_is_controller")]
+fn retry_dead_task(id: u64) {
+    log_caller!(format!("retry_dead_task: {}", id));
+
+    let dead_task = with_dead_tasks(|dead_tasks| dead_tasks.remove(&id))
+        .ok_or("Error::Dead task not found!".to_string())
+        .unwrap_or_else(revert);
+
+    enqueue_task(0, dead_task.into_task());
+}
+
 #[query]
 fn version() -> String {
     env!("CARGO_PKG_VERSION").to_string()
 }
 
-fn schedule_task(after_sec: u64, task: Task) {
-    log_caller!(format!(
-        "schedule_task: {:?} after {} secs",
-        task, after_sec
-    ));
+/// Stores `task` and queues it, recurring tasks that are already queued keep their slot.
+fn enqueue_task(after_sec: u64, task: Task) -> Nonce {
+    log_caller!(format!("enqueue_task: {:?} after {} secs", task, after_sec));
+
+    if task.is_recurring() {
+        if let Some(id) = find_task(&task) {
+            log_cycle!("Task {:?} is already queued as {:?}", task, id);
+            return id;
+        }
+    }
 
     let time = NanoTimeStamp::now().add_secs(after_sec);
 
-    let timer = TaskTimerEntry { task, time };
+    let id = insert_task(task, time.clone()).unwrap_or_else(revert);
+
+    push_timer(id.clone(), time);
+
+    id
+}
+
+/// Moves a stored task to run `after_sec` from now, its previous heap entry goes stale.
+fn requeue_task(after_sec: u64, id: Nonce) {
+    let time = NanoTimeStamp::now().add_secs(after_sec);
+
+    with_scheduled_task(&id, |scheduled| scheduled.set_due_at(time.clone())).unwrap_or_else(revert);
+
+    push_timer(id, time);
+}
+
+fn push_timer(id: Nonce, time: NanoTimeStamp) {
+    let timer = TaskTimerEntry { task: id, time };
 
     with_task_timer(|tt| {
         tt.push_timer(&timer)
@@ -537,12 +1352,12 @@ fn global_timer() {
         }
         with_task_timer(|tt| tt.pop_timer());
 
-        ic_cdk::spawn(execute_task(task_timer));
+        ic_cdk::spawn(run_task(task_timer));
         reschedule();
     }
 }
 
-async fn fetch_encryption_keys() {
+async fn fetch_encryption_keys() -> Result<(), String> {
     log_cycle!("Fetching keys...");
     let symmetric_key = VetKDManagement(ic_cdk::id())
         .request_public_key(vec![b"symmetric_key".to_vec()])
@@ -553,34 +1368,81 @@ async fn fetch_encryption_keys() {
         .await;
 
     log_cycle!("Caching keys...");
-    if let Ok(symmetric_key) = symmetric_key {
-        set_symmetric_encryption_key(symmetric_key);
-    } else {
-        log_cycle!("Failed to fetch symmetric key");
-    }
+    let symmetric_key = symmetric_key
+        .map(set_symmetric_encryption_key)
+        .map_err(|e| format!("Error::Failed to fetch symmetric key: {:?}", e));
+
+    let ibe_encryption_key = ibe_encryption_key
+        .map(set_ibe_encryption_key)
+        .map_err(|e| format!("Error::Failed to fetch ibe encryption key: {:?}", e));
+
+    symmetric_key.and(ibe_encryption_key)
+}
+
+/// Runs a task, re-queueing it with backoff when it fails until it runs out of attempts.
+async fn run_task(timer: TaskTimerEntry<Nonce>) {
+    let id = timer.task;
+
+    let mut scheduled = match take_due_task(&id, &timer.time) {
+        Some(scheduled) => scheduled,
+        None => {
+            log_cycle!("Task {:?} was cancelled or moved, skipping", id);
+            return;
+        }
+    };
+
+    let task = scheduled.task().clone();
+
+    if let Err(e) = execute_task(task.clone()).await {
+        let attempts = scheduled.increment_attempts();
+
+        match Task::retry_in(attempts) {
+            Some(retry_in) => {
+                log_cycle!(
+                    "Task {:?} failed {} times, retrying in {} secs: {}",
+                    task,
+                    attempts,
+                    retry_in,
+                    e
+                );
 
-    if let Ok(ibe_encryption_key) = ibe_encryption_key {
-        set_ibe_encryption_key(ibe_encryption_key);
-    } else {
-        log_cycle!("Failed to fetch ibe encryption key");
+                with_tasks(|tasks| tasks.insert(id.clone(), scheduled));
+
+                requeue_task(retry_in, id);
+            }
+            None => {
+                log_cycle!(
+                    "Task {:?} failed {} times, giving up: {}",
+                    task,
+                    attempts,
+                    e
+                );
+
+                push_dead_task(DeadTask::new(task, attempts, e, NanoTimeStamp::now()));
+            }
+        }
+
+        reschedule();
     }
 }
 
-async fn execute_task(timer: TaskTimerEntry<Task>) {
-    log_cycle!("Execute_task: {:?}", timer);
+async fn execute_task(task: Task) -> Result<(), String> {
+    log_cycle!("Execute_task: {:?}", task);
 
-    match timer.task {
+    match task {
         Task::Initialize => {
             log_cycle!("Initializing...");
 
             let now = NanoTimeStamp::now();
 
-            fetch_encryption_keys().await;
+            fetch_encryption_keys().await?;
 
             log_cycle!("Initializing done! Took: {}ms", now.elapsed().to_millis());
 
-            schedule_task(3600, Task::CleanUpKeys);
-            schedule_task(3600, Task::CleanUpAnonymousUsers);
+            let interval = get_config().clean_up_interval_secs;
+
+            enqueue_task(interval, Task::CleanUpKeys);
+            enqueue_task(interval, Task::CleanUpAnonymousUsers);
 
             reschedule();
         }
@@ -589,17 +1451,60 @@ async fn execute_task(timer: TaskTimerEntry<Task>) {
 
             let now = NanoTimeStamp::now();
 
-            with_one_time_keys(|keys| {
-                let expired_keys: Vec<Nonce> = keys
-                    .iter()
+            let expired_keys: Vec<Nonce> = with_one_time_keys(|keys| {
+                keys.iter()
                     .filter(|(_, key)| key.is_expired())
+                    .map(|(id, _)| id)
+                    .collect()
+            });
+
+            // the share status is refreshed from the keys, so they can't be borrowed meanwhile
+            expired_keys.iter().for_each(|id| {
+                log_cycle!("Removing expired key: {:?}", remove_one_time_key(id));
+
+                refresh_share_status(id);
+            });
+
+            let expired_uploads: Vec<Nonce> = with_chunked_texts(|uploads| {
+                uploads
+                    .iter()
+                    .filter(|(_, upload)| upload.is_expired())
                     .map(|(id, _)| id.clone())
-                    .collect();
+                    .collect()
+            });
 
-                expired_keys.iter().for_each(|id| {
-                    log_cycle!("Removing expired key: {:?}", keys.get(id));
-                    keys.remove(id);
-                });
+            let expired_keys: Vec<[u8; 32]> = with_key_cache(|key_cache| {
+                key_cache
+                    .iter()
+                    .filter(|(_, cached_key)| cached_key.is_expired())
+                    .map(|(cache_id, _)| cache_id)
+                    .collect()
+            });
+
+            with_key_cache(|key_cache| {
+                expired_keys.iter().for_each(|cache_id| {
+                    key_cache.remove(cache_id);
+                })
+            });
+
+            let expired_links: Vec<(Nonce, Nonce)> = with_share_links(|share_links| {
+                share_links
+                    .iter()
+                    .filter(|(_, share_link)| share_link.is_expired())
+                    .map(|(link_id, share_link)| (link_id, share_link.text_id().clone()))
+                    .collect()
+            });
+
+            expired_links.iter().for_each(|(link_id, text_id)| {
+                log_cycle!("Removing expired share link: {:?}", link_id);
+                with_share_links(|share_links| share_links.remove(link_id));
+
+                refresh_share_status(text_id);
+            });
+
+            expired_uploads.iter().for_each(|id| {
+                log_cycle!("Removing abandoned upload: {:?}", id);
+                remove_chunked_text(id);
             });
 
             log_cycle!(
@@ -608,7 +1513,7 @@ async fn execute_task(timer: TaskTimerEntry<Task>) {
             );
 
             // schedule next clean up
-            schedule_task(3600, Task::CleanUpKeys);
+            enqueue_task(get_config().clean_up_interval_secs, Task::CleanUpKeys);
 
             reschedule();
         }
@@ -617,17 +1522,17 @@ async fn execute_task(timer: TaskTimerEntry<Task>) {
 
             let now = NanoTimeStamp::now();
 
-            with_anonymous_users(|users| {
-                let expired_users: Vec<PublicKey> = users
+            let expired_users: Vec<PublicKey> = with_anonymous_users(|users| {
+                users
                     .iter()
                     .filter(|(_, user)| user.is_expired())
                     .map(|(id, _)| id.clone())
-                    .collect();
+                    .collect()
+            });
 
-                expired_users.iter().for_each(|id| {
-                    log_cycle!("Removing expired user: {:?}", users.get(id));
-                    users.remove(id);
-                });
+            expired_users.iter().for_each(|id| {
+                let user = remove_anonymous_user(id);
+                log_cycle!("Removed expired user: {:?}", user);
             });
 
             log_cycle!(
@@ -636,26 +1541,52 @@ async fn execute_task(timer: TaskTimerEntry<Task>) {
             );
 
             // schedule next clean up
-            schedule_task(3600, Task::CleanUpAnonymousUsers);
+            enqueue_task(
+                get_config().clean_up_interval_secs,
+                Task::CleanUpAnonymousUsers,
+            );
 
             reschedule();
         }
         Task::SendEmail {
             email,
-            body,
             subject,
+            body,
         } => {
-            log_cycle!(
-                "sending email to: {} with subject: {}, and body: {}",
+            log_cycle!("Queueing email to: {} with subject: {}", email, subject);
+
+            enqueue_notification(NotificationMessage::Email {
                 email,
                 subject,
-                body
-            );
+                body,
+            })?;
         }
         Task::SendText { phone_number, body } => {
-            log_cycle!("Send text to: {} with body: {}", phone_number, body);
+            log_cycle!("Queueing text to: {}", phone_number);
+
+            enqueue_notification(NotificationMessage::Text { phone_number, body })?;
+        }
+        Task::Notify(id) => {
+            // delivery keeps its own attempts and backoff from the provider settings
+            if let Some(retry_in) = deliver_notification(&id).await {
+                enqueue_task(retry_in, Task::Notify(id));
+
+                reschedule();
+            }
         }
     }
+
+    Ok(())
+}
+
+fn enqueue_notification(message: NotificationMessage) -> Result<(), String> {
+    let id = queue_notification(message)?;
+
+    enqueue_task(0, Task::Notify(id));
+
+    reschedule();
+
+    Ok(())
 }
 
 fn reschedule() {
@@ -0,0 +1,297 @@
+//! Versioned encoding for stable records and the upgrade backup, plus the
+//! migrations `post_upgrade` runs to bring old state up to date.
+//!
+//! Every record is written as `[ENVELOPE_TAG, version, cbor...]`. Records
+//! written before versioning existed have no envelope and are treated as
+//! version 0; their cbor never starts with `ENVELOPE_TAG` (a 19 entry map).
+//!
+//! The bounds of the maps and the task heap that existed before versioning
+//! can't grow, they already leave room for the two envelope bytes.
+use b3_utils::{
+    log_cycle,
+    memory::{
+        timer::TaskTimerEntry,
+        types::{DefaultVMMap, Storable},
+    },
+};
+use ciborium::{de::from_reader, ser::into_writer};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::store::*;
+
+const ENVELOPE_TAG: u8 = 0xB3;
+
+/// Bytes the envelope adds in front of every record.
+pub const ENVELOPE_SIZE: u32 = 2;
+
+/// Version of the whole stable state, stored in the upgrade backup.
+pub const STATE_VERSION: u8 = 2;
+
+/// Migrations keyed by the state version they upgrade from, run in order.
+const MIGRATIONS: [(u8, &str, fn()); 2] = [
+    (0, "wrap stable records in envelopes", rewrite_records),
+    (
+        1,
+        "move task payloads out of the timer heap",
+        move_task_payloads,
+    ),
+];
+
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// The version written by this code, bump it whenever the shape changes.
+    const VERSION: u8;
+
+    /// Decodes a record written by an older `version` of this type.
+    ///
+    /// The default works for additive changes where new fields have `#[serde(default)]`.
+    fn migrate(_version: u8, bytes: &[u8]) -> Result<Self, String> {
+        decode_cbor(bytes)
+    }
+}
+
+pub fn encode<T: Versioned>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![ENVELOPE_TAG, T::VERSION];
+    into_writer(value, &mut bytes).unwrap();
+    bytes
+}
+
+pub fn decode<T: Versioned>(bytes: &[u8]) -> Result<T, String> {
+    match bytes {
+        [ENVELOPE_TAG, version, payload @ ..] if *version == T::VERSION => decode_cbor(payload),
+        [ENVELOPE_TAG, version, payload @ ..] if *version < T::VERSION => {
+            T::migrate(*version, payload)
+        }
+        [ENVELOPE_TAG, version, ..] => Err(format!(
+            "Error::Record version {} is newer than {}!",
+            version,
+            T::VERSION
+        )),
+        legacy => T::migrate(0, legacy),
+    }
+}
+
+/// Returns the version a record or backup was written with.
+pub fn version_of(bytes: &[u8]) -> u8 {
+    match bytes {
+        [ENVELOPE_TAG, version, ..] => *version,
+        _ => 0,
+    }
+}
+
+fn decode_cbor<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    from_reader(bytes).map_err(|e| format!("Error::Failed to decode record: {}", e))
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateBackup {
+    pub ibe_key: Vec<u8>,
+    pub sym_key: Vec<u8>,
+}
+
+impl Versioned for StateBackup {
+    const VERSION: u8 = STATE_VERSION;
+
+    fn migrate(version: u8, bytes: &[u8]) -> Result<Self, String> {
+        match version {
+            // the first backups were a bare `(ibe_key, sym_key)` tuple
+            0 => {
+                let (ibe_key, sym_key) = decode_cbor(bytes)?;
+
+                Ok(Self { ibe_key, sym_key })
+            }
+            _ => decode_cbor(bytes),
+        }
+    }
+}
+
+/// Runs every migration registered for a state older than `STATE_VERSION`.
+pub fn run_migrations(from_version: u8) {
+    for (version, name, migration) in MIGRATIONS.iter() {
+        if *version >= from_version {
+            log_cycle!("Migrating state from v{}: {}", version, name);
+
+            migration();
+        }
+    }
+}
+
+/// Reads and writes back every record so it is stored at its current version.
+fn rewrite_records() {
+    with_users(rewrite_map);
+    with_anonymous_users(rewrite_map);
+    with_one_time_keys(rewrite_map);
+    with_chunked_texts(rewrite_map);
+    with_dead_tasks(rewrite_map);
+    with_key_cache(rewrite_map);
+    with_note_access_logs(rewrite_map);
+    with_note_metas(rewrite_map);
+    with_note_recipients(rewrite_map);
+    with_notifications(rewrite_map);
+    with_roles(rewrite_map);
+    with_share_links(rewrite_map);
+    with_simple_notes(rewrite_map);
+    with_tasks(rewrite_map);
+    with_tombstones(rewrite_map);
+
+    with_legacy_task_timer(|tt| {
+        let timers = tt.get_timers();
+
+        while tt.pop_timer().is_some() {}
+
+        for timer in timers {
+            if tt.push_timer(&timer).is_err() {
+                log_cycle!("Failed to rewrite timer: {:?}", timer);
+            }
+        }
+    });
+}
+
+/// Drains the old heap of whole tasks into the task map and the heap of ids.
+fn move_task_payloads() {
+    let timers = with_legacy_task_timer(|tt| {
+        let timers = tt.get_timers();
+
+        while tt.pop_timer().is_some() {}
+
+        timers
+    });
+
+    for TaskTimerEntry { task, time } in timers {
+        let pushed = insert_task(task.0.clone(), time.clone()).and_then(|id| {
+            with_task_timer(|tt| tt.push_timer(&TaskTimerEntry { task: id, time }))
+                .map_err(|_| "Error::Failed to push timer!".to_string())
+        });
+
+        if let Err(e) = pushed {
+            log_cycle!("Failed to move task {:?}: {}", task, e);
+        }
+    }
+}
+
+fn rewrite_map<K, V>(map: &mut DefaultVMMap<K, V>)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let keys: Vec<K> = map.iter().map(|(key, _)| key).collect();
+
+    for key in keys {
+        if let Some(value) = map.get(&key) {
+            map.insert(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::*;
+    use b3_utils::nonce::Nonce;
+
+    fn fixture(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_unversioned_anonymous_user() {
+        // {"texts": [1, 2], "created_at": 5, "decryption_key": null}
+        let bytes = fixture(
+            "a3657465787473820102\
+             6a637265617465645f617405\
+             6e64656372797074696f6e5f6b6579f6",
+        );
+
+        assert_eq!(version_of(&bytes), 0);
+
+        let user: AnonymousUserData = decode(&bytes).unwrap();
+
+        assert_eq!(
+            user.iter_texts().cloned().collect::<Vec<_>>(),
+            vec![Nonce::from(1), Nonce::from(2)]
+        );
+    }
+
+    #[test]
+    fn decodes_v1_anonymous_user_with_decryption_key() {
+        // v1 {"texts": [3], "created_at": 5, "decryption_key": [7]}
+        let bytes = fixture(
+            "b301a36574657874738103\
+             6a637265617465645f617405\
+             6e64656372797074696f6e5f6b65798107",
+        );
+
+        assert_eq!(version_of(&bytes), 1);
+
+        let user: AnonymousUserData = decode(&bytes).unwrap();
+
+        assert_eq!(
+            user.iter_texts().cloned().collect::<Vec<_>>(),
+            vec![Nonce::from(3)]
+        );
+    }
+
+    #[test]
+    fn decodes_unversioned_task_with_padding() {
+        // "Initialize", padded with zeros to the fixed task size
+        let mut bytes = fixture("6a496e697469616c697a65");
+        bytes.resize(24, 0);
+
+        let task: Task = decode(&bytes).unwrap();
+
+        assert_eq!(task, Task::Initialize);
+    }
+
+    #[test]
+    fn decodes_unversioned_backup() {
+        // ([1, 2], [3])
+        let bytes = fixture("828201028103");
+
+        assert_eq!(version_of(&bytes), 0);
+
+        let backup: StateBackup = decode(&bytes).unwrap();
+
+        assert_eq!(
+            backup,
+            StateBackup {
+                ibe_key: vec![1, 2],
+                sym_key: vec![3],
+            }
+        );
+    }
+
+    #[test]
+    fn round_trips_current_version() {
+        let backup = StateBackup {
+            ibe_key: vec![4; 96],
+            sym_key: vec![5; 96],
+        };
+
+        let bytes = encode(&backup);
+
+        assert_eq!(version_of(&bytes), STATE_VERSION);
+        assert_eq!(decode::<StateBackup>(&bytes).unwrap(), backup);
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let mut bytes = encode(&Task::CleanUpKeys);
+        bytes[1] = u8::MAX;
+
+        assert!(decode::<Task>(&bytes).is_err());
+    }
+
+    #[test]
+    fn legacy_tasks_fit_fixed_task_size() {
+        for task in [
+            Task::Initialize,
+            Task::CleanUpAnonymousUsers,
+            Task::CleanUpKeys,
+            Task::Notify(Nonce::from(u64::MAX)),
+        ] {
+            assert!(LegacyTask(task).to_bytes().len() <= 24);
+        }
+    }
+}
@@ -0,0 +1,494 @@
+//! Email and text delivery through the configured provider's HTTPS endpoint.
+//!
+//! Every replica sends the request, so the notification id goes along as an
+//! `Idempotency-Key` header and the provider is expected to drop duplicates.
+use b3_utils::{log_cycle, nonce::Nonce, NanoTimeStamp};
+use candid::{Func, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse,
+    TransformContext, TransformFunc,
+};
+use std::future::Future;
+
+use crate::{store::*, types::*};
+
+const NOTIFICATION_CYCLES: u128 = 2_000_000_000;
+const NOTIFICATION_MAX_RESPONSE_BYTES: u64 = 2048;
+
+pub const TRANSFORM_METHOD: &str = "transform_notification";
+
+/// Stores a notification and returns its id, the caller schedules delivery.
+pub fn queue_notification(message: NotificationMessage) -> Result<Nonce, String> {
+    let notification = Notification::new(message, NanoTimeStamp::now())?;
+
+    let id = increment_notification_id()?;
+
+    with_notifications(|notifications| notifications.insert(id.clone(), notification));
+
+    Ok(id)
+}
+
+/// Makes one delivery attempt and returns in how many seconds to retry, if at all.
+pub async fn deliver_notification(id: &Nonce) -> Option<u64> {
+    let provider = get_notification_provider();
+
+    let send = |request| async move {
+        http_request(request, NOTIFICATION_CYCLES)
+            .await
+            .map(|(response,)| response)
+            .map_err(|(code, message)| format!("{:?}: {}", code, message))
+    };
+
+    match attempt_delivery(ic_cdk::id(), id, &provider, NanoTimeStamp::now(), send).await {
+        Some((outcome, retry_in)) => {
+            log_cycle!("Notification {:?} attempt: {:?}", id, outcome);
+
+            retry_in
+        }
+        None => {
+            log_cycle!("Notification {:?} is not pending, skipping", id);
+
+            None
+        }
+    }
+}
+
+/// Sends a pending notification through `send` and records the outcome,
+/// `canister` is the canister that answers the transform query.
+///
+/// Returns None if the notification is not pending, otherwise the outcome and
+/// in how many seconds to retry, if at all.
+pub async fn attempt_delivery<F, Fut>(
+    canister: Principal,
+    id: &Nonce,
+    provider: &NotificationProvider,
+    now: NanoTimeStamp,
+    send: F,
+) -> Option<(DeliveryOutcome, Option<u64>)>
+where
+    F: FnOnce(CanisterHttpRequestArgument) -> Fut,
+    Fut: Future<Output = Result<HttpResponse, String>>,
+{
+    let notification = with_notifications(|notifications| notifications.get(id))
+        .filter(|notification| notification.is_pending())?;
+
+    let outcome = if provider.is_configured() {
+        match send(build_request(
+            canister,
+            provider,
+            id,
+            notification.message(),
+        ))
+        .await
+        {
+            Ok(response) => classify_response(&response),
+            Err(error) => DeliveryOutcome::Retry(error),
+        }
+    } else {
+        DeliveryOutcome::Retry("Error::Notification provider is not configured!".to_string())
+    };
+
+    let retry_in = with_notification(id, |notification| {
+        notification.record_attempt(outcome.clone(), provider, now)
+    })
+    .unwrap_or(None);
+
+    Some((outcome, retry_in))
+}
+
+pub fn build_request(
+    canister: Principal,
+    provider: &NotificationProvider,
+    id: &Nonce,
+    message: &NotificationMessage,
+) -> CanisterHttpRequestArgument {
+    let body = match message {
+        NotificationMessage::Email {
+            email,
+            subject,
+            body,
+        } => serde_json::json!({
+            "kind": "email",
+            "to": email,
+            "subject": subject,
+            "body": body,
+        }),
+        NotificationMessage::Text { phone_number, body } => serde_json::json!({
+            "kind": "text",
+            "to": phone_number,
+            "body": body,
+        }),
+    };
+
+    let mut headers = vec![
+        HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
+        HttpHeader {
+            name: "Idempotency-Key".to_string(),
+            value: id.to_string(),
+        },
+    ];
+
+    if let Some(token) = &provider.auth_token {
+        headers.push(HttpHeader {
+            name: "Authorization".to_string(),
+            value: format!("Bearer {}", token),
+        });
+    }
+
+    CanisterHttpRequestArgument {
+        url: provider.endpoint.clone(),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(body.to_string().into_bytes()),
+        max_response_bytes: Some(NOTIFICATION_MAX_RESPONSE_BYTES),
+        transform: Some(TransformContext {
+            function: TransformFunc(Func {
+                principal: canister,
+                method: TRANSFORM_METHOD.to_string(),
+            }),
+            context: vec![],
+        }),
+    }
+}
+
+/// 2xx is delivered, 429 and 5xx are worth retrying, any other status is final.
+pub fn classify_response(response: &HttpResponse) -> DeliveryOutcome {
+    let status = response.status.to_string().parse::<u16>().unwrap_or(0);
+
+    match status {
+        200..=299 => DeliveryOutcome::Delivered,
+        429 | 500..=599 => DeliveryOutcome::Retry(format!("Provider responded with {}", status)),
+        _ => DeliveryOutcome::Failed(format!("Provider rejected with {}", status)),
+    }
+}
+
+/// Keeps only the status so every replica agrees on the response.
+pub fn transform_response(response: HttpResponse) -> HttpResponse {
+    HttpResponse {
+        status: response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider() -> NotificationProvider {
+        NotificationProvider {
+            endpoint: "https://notify.example.com/send".to_string(),
+            auth_token: Some("secret".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn canister() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    fn header(name: &str, value: &str) -> HttpHeader {
+        HttpHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn transform() -> Option<TransformContext> {
+        Some(TransformContext {
+            function: TransformFunc(Func {
+                principal: canister(),
+                method: "transform_notification".to_string(),
+            }),
+            context: vec![],
+        })
+    }
+
+    fn pending_notification(id: u64) -> Nonce {
+        let id = Nonce::from(id);
+
+        let message = NotificationMessage::Text {
+            phone_number: "+15550100".to_string(),
+            body: "123456".to_string(),
+        };
+
+        let notification = Notification::new(message, NanoTimeStamp::default()).unwrap();
+
+        with_notifications(|notifications| notifications.insert(id.clone(), notification));
+
+        id
+    }
+
+    fn notification(id: &Nonce) -> Notification {
+        with_notifications(|notifications| notifications.get(id)).unwrap()
+    }
+
+    /// Polls a future that never waits, which the mock provider's never do.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+        fn raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                raw_waker()
+            }
+            fn noop(_: *const ()) {}
+
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+
+        let waker = unsafe { Waker::from_raw(raw_waker()) };
+        let mut context = Context::from_waker(&waker);
+
+        match Box::pin(future).as_mut().poll(&mut context) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("Mock provider should answer right away"),
+        }
+    }
+
+    /// Makes a delivery attempt against a mock provider that answers with
+    /// `reply`, a status or a transport error, and keeps the request it got.
+    fn attempt(
+        id: &Nonce,
+        provider: &NotificationProvider,
+        reply: Result<u64, &str>,
+        sent: &mut Vec<CanisterHttpRequestArgument>,
+    ) -> Option<(DeliveryOutcome, Option<u64>)> {
+        let send = |request| {
+            sent.push(request);
+
+            let reply = reply.map(response).map_err(|error| error.to_string());
+
+            async move { reply }
+        };
+
+        block_on(attempt_delivery(
+            canister(),
+            id,
+            provider,
+            NanoTimeStamp::default(),
+            send,
+        ))
+    }
+
+    fn response(status: u64) -> HttpResponse {
+        HttpResponse {
+            status: status.into(),
+            headers: vec![HttpHeader {
+                name: "Date".to_string(),
+                value: "Sun, 18 Oct 2026 00:00:00 GMT".to_string(),
+            }],
+            body: b"{\"id\":\"random\"}".to_vec(),
+        }
+    }
+
+    #[test]
+    fn builds_email_request() {
+        let message = NotificationMessage::Email {
+            email: "alice@example.com".to_string(),
+            subject: "Your code".to_string(),
+            body: "123456".to_string(),
+        };
+
+        let request = build_request(canister(), &provider(), &Nonce::from(7), &message);
+
+        assert_eq!(
+            request,
+            CanisterHttpRequestArgument {
+                url: "https://notify.example.com/send".to_string(),
+                max_response_bytes: Some(2048),
+                method: HttpMethod::POST,
+                headers: vec![
+                    header("Content-Type", "application/json"),
+                    header("Idempotency-Key", "7"),
+                    header("Authorization", "Bearer secret"),
+                ],
+                body: Some(
+                    br#"{"body":"123456","kind":"email","subject":"Your code","to":"alice@example.com"}"#
+                        .to_vec()
+                ),
+                transform: transform(),
+            }
+        );
+
+        // the provider callers are shown never carries the real token
+        let redacted = build_request(
+            canister(),
+            &provider().redacted(),
+            &Nonce::from(7),
+            &message,
+        );
+
+        assert_eq!(
+            redacted.headers.last(),
+            Some(&header("Authorization", "Bearer ********"))
+        );
+    }
+
+    #[test]
+    fn builds_text_request_without_token() {
+        let provider = NotificationProvider {
+            auth_token: None,
+            ..provider()
+        };
+
+        let message = NotificationMessage::Text {
+            phone_number: "+15550100".to_string(),
+            body: "123456".to_string(),
+        };
+
+        let request = build_request(canister(), &provider, &Nonce::from(1), &message);
+
+        assert_eq!(
+            request,
+            CanisterHttpRequestArgument {
+                url: "https://notify.example.com/send".to_string(),
+                max_response_bytes: Some(2048),
+                method: HttpMethod::POST,
+                headers: vec![
+                    header("Content-Type", "application/json"),
+                    header("Idempotency-Key", "1"),
+                ],
+                body: Some(br#"{"body":"123456","kind":"text","to":"+15550100"}"#.to_vec()),
+                transform: transform(),
+            }
+        );
+    }
+
+    #[test]
+    fn classifies_provider_responses() {
+        assert_eq!(
+            classify_response(&response(202)),
+            DeliveryOutcome::Delivered
+        );
+        assert!(matches!(
+            classify_response(&response(429)),
+            DeliveryOutcome::Retry(_)
+        ));
+        assert!(matches!(
+            classify_response(&response(503)),
+            DeliveryOutcome::Retry(_)
+        ));
+        assert!(matches!(
+            classify_response(&response(400)),
+            DeliveryOutcome::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn transform_keeps_only_status() {
+        let transformed = transform_response(response(200));
+
+        assert_eq!(transformed.status, response(200).status);
+        assert!(transformed.headers.is_empty());
+        assert!(transformed.body.is_empty());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_cap() {
+        let provider = provider();
+
+        assert_eq!(provider.backoff_secs(1), 30);
+        assert_eq!(provider.backoff_secs(2), 60);
+        assert_eq!(provider.backoff_secs(3), 120);
+        assert_eq!(provider.backoff_secs(u8::MAX), 60 * 60);
+    }
+
+    #[test]
+    fn delivers_once_provider_accepts() {
+        let id = pending_notification(1);
+        let mut sent = vec![];
+
+        assert_eq!(
+            attempt(&id, &provider(), Ok(200), &mut sent),
+            Some((DeliveryOutcome::Delivered, None))
+        );
+        assert_eq!(notification(&id).status(), DeliveryStatus::Delivered);
+        assert_eq!(notification(&id).attempts(), 1);
+
+        // delivered notifications are never sent again
+        assert_eq!(attempt(&id, &provider(), Ok(200), &mut sent), None);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0],
+            build_request(canister(), &provider(), &id, notification(&id).message())
+        );
+    }
+
+    #[test]
+    fn retries_server_errors_with_backoff() {
+        let id = pending_notification(2);
+        let mut sent = vec![];
+
+        let (outcome, retry_in) = attempt(&id, &provider(), Ok(503), &mut sent).unwrap();
+        assert!(matches!(outcome, DeliveryOutcome::Retry(_)));
+        assert_eq!(retry_in, Some(30));
+
+        let (outcome, retry_in) =
+            attempt(&id, &provider(), Err("SysTransient"), &mut sent).unwrap();
+        assert_eq!(outcome, DeliveryOutcome::Retry("SysTransient".to_string()));
+        assert_eq!(retry_in, Some(60));
+        assert!(notification(&id).is_pending());
+
+        assert_eq!(
+            attempt(&id, &provider(), Ok(202), &mut sent),
+            Some((DeliveryOutcome::Delivered, None))
+        );
+
+        let notification = notification(&id);
+        assert_eq!(notification.status(), DeliveryStatus::Delivered);
+        assert_eq!(notification.attempts(), 3);
+        assert_eq!(notification.last_error(), None);
+        assert_eq!(sent.len(), 3);
+    }
+
+    #[test]
+    fn fails_after_max_attempts() {
+        let provider = NotificationProvider {
+            max_attempts: 3,
+            ..provider()
+        };
+        let id = pending_notification(3);
+        let mut sent = vec![];
+
+        for retry_in in [Some(30), Some(60), None] {
+            let (_, next) = attempt(&id, &provider, Ok(500), &mut sent).unwrap();
+            assert_eq!(next, retry_in);
+        }
+
+        let notification = notification(&id);
+        assert_eq!(notification.status(), DeliveryStatus::Failed);
+        assert_eq!(notification.attempts(), 3);
+        assert_eq!(
+            notification.last_error(),
+            Some("Provider responded with 500")
+        );
+
+        assert_eq!(attempt(&id, &provider, Ok(200), &mut sent), None);
+        assert_eq!(sent.len(), 3);
+    }
+
+    #[test]
+    fn rejected_notifications_fail_right_away() {
+        let id = pending_notification(4);
+        let mut sent = vec![];
+
+        let (outcome, retry_in) = attempt(&id, &provider(), Ok(400), &mut sent).unwrap();
+        assert!(matches!(outcome, DeliveryOutcome::Failed(_)));
+        assert_eq!(retry_in, None);
+        assert_eq!(notification(&id).status(), DeliveryStatus::Failed);
+    }
+
+    #[test]
+    fn redacts_auth_token() {
+        assert_eq!(
+            provider().redacted().auth_token,
+            Some("********".to_string())
+        );
+        assert_eq!(NotificationProvider::default().redacted().auth_token, None);
+    }
+}
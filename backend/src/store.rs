@@ -1,33 +1,66 @@
 use b3_utils::{
+    log_cycle,
     memory::{
         init_stable_mem_refcell,
         timer::DefaultTaskTimer,
         types::{DefaultVMCell, DefaultVMMap},
     },
     nonce::Nonce,
-    Subaccount,
+    NanoTimeStamp, Subaccount,
 };
-use std::{cell::RefCell, collections::HashMap, ops::Add};
+use candid::Principal;
+use std::{cell::RefCell, collections::BTreeSet, ops::Add};
 
-use crate::{types::*, utils::vec_to_fixed_array};
+use crate::{
+    types::*,
+    utils::{get_anonymous_user, vec_to_fixed_array},
+};
 
 thread_local! {
     pub static TEXT_COUNTER: RefCell<DefaultVMCell<Nonce>> = init_stable_mem_refcell("stable_counter", 100).unwrap();
+    pub static CONFIG: RefCell<DefaultVMCell<Config>> = init_stable_mem_refcell("config", 101).unwrap();
+    pub static NOTIFICATION_COUNTER: RefCell<DefaultVMCell<Nonce>> = init_stable_mem_refcell("notification_counter", 102).unwrap();
+    pub static TASK_COUNTER: RefCell<DefaultVMCell<Nonce>> = init_stable_mem_refcell("task_counter", 104).unwrap();
+    pub static SHARE_LINK_COUNTER: RefCell<DefaultVMCell<Nonce>> = init_stable_mem_refcell("share_link_counter", 105).unwrap();
+    pub static NOTIFICATION_PROVIDER: RefCell<DefaultVMCell<NotificationProvider>> = init_stable_mem_refcell("notify_provider", 103).unwrap();
 
     pub static IBE_ENCRYPTION_KEYS: RefCell<EncryptionKey> = RefCell::new([0; 96]);
     pub static SYMMETRIC_ENCRYPTION_KEYS: RefCell<EncryptionKey> = RefCell::new([0; 96]);
 
-    pub static TASK_TIMER: RefCell<DefaultTaskTimer<Task>> = init_stable_mem_refcell("task_timer", 1).unwrap();
+    pub static LEGACY_TASK_TIMER: RefCell<DefaultTaskTimer<LegacyTask>> = init_stable_mem_refcell("task_timer", 1).unwrap();
+    pub static TASK_TIMER: RefCell<DefaultTaskTimer<Nonce>> = init_stable_mem_refcell("task_queue", 25).unwrap();
 
     pub static USERS: RefCell<DefaultVMMap<Subaccount, UserData>> = init_stable_mem_refcell("users", 10).unwrap();
     pub static ANONYMOUS_USERS: RefCell<DefaultVMMap<PublicKey, AnonymousUserData>> = init_stable_mem_refcell("anonymous_users", 11).unwrap();
+    pub static CHALLENGE_COUNTERS: RefCell<DefaultVMMap<PublicKey, u64>> = init_stable_mem_refcell("challenge_counters", 30).unwrap();
+    pub static CLAIMED_IDENTITIES: RefCell<DefaultVMMap<PublicKey, Subaccount>> = init_stable_mem_refcell("claimed_identities", 32).unwrap();
 
     pub static USER_PASS: RefCell<DefaultVMMap<UserName, EncryptedHashedPassword>> = init_stable_mem_refcell("password", 12).unwrap();
 
     pub static ONE_TIME_KEYS: RefCell<DefaultVMMap<Nonce, OneTimeKey>> = init_stable_mem_refcell("one_time_key", 13).unwrap();
+    pub static ONE_TIME_WRAPPED_KEYS: RefCell<DefaultVMMap<Nonce, WrappedKey>> = init_stable_mem_refcell("one_time_wrapped_key", 31).unwrap();
     pub static ENCRYPTED_TEXTS: RefCell<DefaultVMMap<Nonce, EncryptedText>> = init_stable_mem_refcell("text", 14).unwrap();
 
-    pub static SIMPLE_NOTES: RefCell<HashMap<PublicKey, Vec<String>>> = RefCell::new(HashMap::new());
+    pub static CHUNKED_TEXTS: RefCell<DefaultVMMap<Nonce, ChunkedText>> = init_stable_mem_refcell("chunked_text", 15).unwrap();
+    pub static ENCRYPTED_CHUNKS: RefCell<DefaultVMMap<ChunkKey, EncryptedChunk>> = init_stable_mem_refcell("text_chunk", 16).unwrap();
+
+    pub static NOTE_METAS: RefCell<DefaultVMMap<Nonce, NoteMeta>> = init_stable_mem_refcell("note_meta", 17).unwrap();
+
+    pub static ROLES: RefCell<DefaultVMMap<Subaccount, RoleGrant>> = init_stable_mem_refcell("roles", 18).unwrap();
+
+    pub static NOTE_RECIPIENTS: RefCell<DefaultVMMap<Nonce, NoteRecipients>> = init_stable_mem_refcell("note_recipients", 20).unwrap();
+
+    pub static TASKS: RefCell<DefaultVMMap<Nonce, ScheduledTask>> = init_stable_mem_refcell("tasks", 24).unwrap();
+    pub static DEAD_TASKS: RefCell<DefaultVMMap<u64, DeadTask>> = init_stable_mem_refcell("dead_tasks", 23).unwrap();
+
+    pub static NOTE_ACCESS_LOGS: RefCell<DefaultVMMap<Nonce, NoteAccessLog>> = init_stable_mem_refcell("note_access_logs", 27).unwrap();
+    pub static SHARE_LINKS: RefCell<DefaultVMMap<Nonce, ShareLink>> = init_stable_mem_refcell("share_links", 28).unwrap();
+    pub static KEY_CACHE: RefCell<DefaultVMMap<[u8; 32], CachedKey>> = init_stable_mem_refcell("key_cache", 29).unwrap();
+    pub static TOMBSTONES: RefCell<DefaultVMMap<Nonce, NoteTombstone>> = init_stable_mem_refcell("tombstones", 26).unwrap();
+
+    pub static NOTIFICATIONS: RefCell<DefaultVMMap<Nonce, Notification>> = init_stable_mem_refcell("notifications", 21).unwrap();
+
+    pub static SIMPLE_NOTES: RefCell<DefaultVMMap<PublicKey, SimpleNotes>> = init_stable_mem_refcell("simple_notes", 19).unwrap();
 }
 
 pub fn increment_nonce() -> Result<Nonce, String> {
@@ -44,6 +77,52 @@ pub fn increment_nonce() -> Result<Nonce, String> {
     })
 }
 
+pub fn increment_notification_id() -> Result<Nonce, String> {
+    NOTIFICATION_COUNTER.with(|nonce| {
+        let mut nonce = nonce.borrow_mut();
+
+        let current = nonce.get().add(Nonce::from(1));
+
+        let next = nonce
+            .set(current)
+            .map_err(|_| "Error::Notification counter overflowed!")?;
+
+        Ok(next)
+    })
+}
+
+pub fn get_notification_provider() -> NotificationProvider {
+    NOTIFICATION_PROVIDER.with(|provider| provider.borrow().get().clone())
+}
+
+pub fn set_notification_provider(provider: NotificationProvider) -> Result<(), String> {
+    provider.validate()?;
+
+    NOTIFICATION_PROVIDER.with(|cell| {
+        cell.borrow_mut()
+            .set(provider)
+            .map_err(|_| "Error::Failed to store notification provider!".to_string())
+    })?;
+
+    Ok(())
+}
+
+pub fn get_config() -> Config {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+pub fn set_config(config: Config) -> Result<(), String> {
+    config.validate()?;
+
+    CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .map_err(|_| "Error::Failed to store config!".to_string())
+    })?;
+
+    Ok(())
+}
+
 pub fn set_ibe_encryption_key(key: Vec<u8>) {
     let key = vec_to_fixed_array(&key).unwrap();
 
@@ -68,6 +147,13 @@ pub fn get_symmetric_encrypted_key() -> EncryptionKey {
     SYMMETRIC_ENCRYPTION_KEYS.with(|symmetric_encrypted_key| *symmetric_encrypted_key.borrow())
 }
 
+pub fn with_simple_notes<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<PublicKey, SimpleNotes>) -> R,
+{
+    SIMPLE_NOTES.with(|simple_notes| f(&mut *simple_notes.borrow_mut()))
+}
+
 pub fn with_anonymous_users<F, R>(f: F) -> R
 where
     F: FnOnce(&mut DefaultVMMap<PublicKey, AnonymousUserData>) -> R,
@@ -93,7 +179,7 @@ where
     with_anonymous_users(|anonymous_users| {
         let mut anonymous_user = anonymous_users
             .get(public_key)
-            .unwrap_or_else(|| AnonymousUserData::new(None));
+            .unwrap_or_else(AnonymousUserData::new);
 
         let result = f(&mut anonymous_user);
 
@@ -103,13 +189,111 @@ where
     })
 }
 
+pub fn with_challenge_counters<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<PublicKey, u64>) -> R,
+{
+    CHALLENGE_COUNTERS.with(|counters| f(&mut *counters.borrow_mut()))
+}
+
+/// Counters are kept apart from the anonymous user and never removed, so a
+/// proof can't be replayed once its user expired or was claimed.
+pub fn get_challenge_counter(public_key: &PublicKey) -> u64 {
+    with_challenge_counters(|counters| counters.get(public_key)).unwrap_or(0)
+}
+
+/// Consumes the current challenge of `public_key`.
+pub fn increment_challenge_counter(public_key: &PublicKey) {
+    with_challenge_counters(|counters| {
+        let counter = counters.get(public_key).unwrap_or(0);
+
+        counters.insert(*public_key, counter + 1);
+    })
+}
+
 pub fn with_task_timer<F, R>(f: F) -> R
 where
-    F: FnOnce(&mut DefaultTaskTimer<Task>) -> R,
+    F: FnOnce(&mut DefaultTaskTimer<Nonce>) -> R,
 {
     TASK_TIMER.with(|task_timer| f(&mut *task_timer.borrow_mut()))
 }
 
+pub fn with_legacy_task_timer<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultTaskTimer<LegacyTask>) -> R,
+{
+    LEGACY_TASK_TIMER.with(|task_timer| f(&mut *task_timer.borrow_mut()))
+}
+
+pub fn with_tasks<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<Nonce, ScheduledTask>) -> R,
+{
+    TASKS.with(|tasks| f(&mut *tasks.borrow_mut()))
+}
+
+pub fn with_scheduled_task<F, R>(id: &Nonce, f: F) -> Result<R, String>
+where
+    F: FnOnce(&mut ScheduledTask) -> R,
+{
+    with_tasks(|tasks| {
+        let mut task = tasks.get(id).ok_or("Error::Task not found!".to_string())?;
+
+        let result = f(&mut task);
+
+        tasks.insert(id.clone(), task);
+
+        Ok(result)
+    })
+}
+
+pub fn increment_task_id() -> Result<Nonce, String> {
+    TASK_COUNTER.with(|nonce| {
+        let mut nonce = nonce.borrow_mut();
+
+        let current = nonce.get().add(Nonce::from(1));
+
+        let next = nonce
+            .set(current)
+            .map_err(|_| "Error::Task counter overflowed!")?;
+
+        Ok(next)
+    })
+}
+
+/// Stores the payload of `task` and returns the id to push onto the timer heap.
+pub fn insert_task(task: Task, due_at: NanoTimeStamp) -> Result<Nonce, String> {
+    let id = increment_task_id()?;
+
+    with_tasks(|tasks| tasks.insert(id.clone(), ScheduledTask::new(task, due_at)));
+
+    Ok(id)
+}
+
+/// Cancels a task, its heap entry is skipped once it has no payload.
+pub fn remove_task(id: &Nonce) -> Result<ScheduledTask, String> {
+    with_tasks(|tasks| tasks.remove(id)).ok_or("Error::Task not found!".to_string())
+}
+
+/// Takes out the payload of a heap entry that is due, None if it was cancelled or moved.
+///
+/// The payload is taken out while running, so a recurring task can queue its next run.
+pub fn take_due_task(id: &Nonce, time: &NanoTimeStamp) -> Option<ScheduledTask> {
+    with_tasks(|tasks| match tasks.get(id) {
+        Some(scheduled) if scheduled.is_due_at(time) => tasks.remove(id),
+        _ => None,
+    })
+}
+
+pub fn find_task(task: &Task) -> Option<Nonce> {
+    with_tasks(|tasks| {
+        tasks
+            .iter()
+            .find(|(_, scheduled)| scheduled.task() == task)
+            .map(|(id, _)| id)
+    })
+}
+
 pub fn with_users<F, R>(f: F) -> R
 where
     F: FnOnce(&mut DefaultVMMap<Subaccount, UserData>) -> R,
@@ -183,15 +367,1244 @@ pub fn with_one_time_key_and_try<F, R>(text_id: &Nonce, f: F) -> Result<R, Strin
 where
     F: FnOnce(&mut OneTimeKey) -> Result<R, String>,
 {
-    with_one_time_keys(|one_time_key| {
-        let mut one_time_key = one_time_key
+    with_one_time_keys(|one_time_keys| {
+        let mut one_time_key = one_time_keys
             .get(text_id)
-            .ok_or("Error::Text not found!".to_string())?;
+            .ok_or("Error::One time key not found!".to_string())?;
 
         let result = f(&mut one_time_key);
 
         one_time_key.add_try();
 
+        one_time_keys.insert(text_id.clone(), one_time_key);
+
         result
     })
 }
+
+/// Counts a read of a one time key, refusing it once the key is out of tries or expired.
+///
+/// Refused reads are recorded in the note's access log.
+pub fn try_one_time_key(
+    text_id: &Nonce,
+    reader: RecipientIdentity,
+    now: NanoTimeStamp,
+) -> Result<OneTimeKey, String> {
+    let one_time_key = with_one_time_key_and_try(text_id, |key| Ok(key.clone()))?;
+
+    let (outcome, error) = if one_time_key.out_of_tries() {
+        (
+            AccessOutcome::OutOfTries,
+            "Error::One time key is out of tries!",
+        )
+    } else if one_time_key.is_expired_at(&now) {
+        (AccessOutcome::Expired, "Error::One time key is expired!")
+    } else {
+        return Ok(one_time_key);
+    };
+
+    record_note_access(text_id, AccessEntry::for_reader(reader, outcome, now));
+
+    Err(error.to_string())
+}
+
+pub fn with_one_time_wrapped_keys<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<Nonce, WrappedKey>) -> R,
+{
+    ONE_TIME_WRAPPED_KEYS.with(|wrapped_keys| f(&mut *wrapped_keys.borrow_mut()))
+}
+
+/// Uses up the one time key of a note, returning it with its wrapped key.
+///
+/// Reads race while they wait for their encrypted key, only the first one to
+/// get here gets the note.
+pub fn take_one_time_key(text_id: &Nonce) -> Result<(OneTimeKey, Option<Vec<u8>>), String> {
+    let wrapped_key = with_one_time_wrapped_keys(|wrapped_keys| wrapped_keys.get(text_id))
+        .map(WrappedKey::into_inner);
+
+    remove_one_time_key(text_id)
+        .map(|one_time_key| (one_time_key, wrapped_key))
+        .ok_or("Error::One time key was already used!".to_string())
+}
+
+/// Removes the one time key of a note together with its wrapped key.
+pub fn remove_one_time_key(text_id: &Nonce) -> Option<OneTimeKey> {
+    with_one_time_wrapped_keys(|wrapped_keys| wrapped_keys.remove(text_id));
+
+    with_one_time_keys(|keys| keys.remove(text_id))
+}
+
+pub fn with_chunked_texts<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<Nonce, ChunkedText>) -> R,
+{
+    CHUNKED_TEXTS.with(|chunked_texts| f(&mut *chunked_texts.borrow_mut()))
+}
+
+pub fn with_chunked_text<F, R>(text_id: &Nonce, f: F) -> Result<R, String>
+where
+    F: FnOnce(&mut ChunkedText) -> Result<R, String>,
+{
+    with_chunked_texts(|chunked_texts| {
+        let mut chunked_text = chunked_texts
+            .get(text_id)
+            .ok_or("Error::Upload not found!".to_string())?;
+
+        let result = f(&mut chunked_text)?;
+
+        chunked_texts.insert(text_id.clone(), chunked_text);
+
+        Ok(result)
+    })
+}
+
+pub fn with_encrypted_chunks<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<ChunkKey, EncryptedChunk>) -> R,
+{
+    ENCRYPTED_CHUNKS.with(|encrypted_chunks| f(&mut *encrypted_chunks.borrow_mut()))
+}
+
+/// Whether `text_id` was uploaded in chunks, those are only streamed with `get_encrypted_text_chunk`.
+pub fn is_chunked_text(text_id: &Nonce) -> bool {
+    with_chunked_texts(|chunked_texts| chunked_texts.contains_key(text_id))
+}
+
+/// Removes a chunked text together with all of its chunks, committed or not.
+pub fn remove_chunked_text(text_id: &Nonce) -> Option<ChunkedText> {
+    let chunked_text = with_chunked_texts(|chunked_texts| chunked_texts.remove(text_id))?;
+
+    with_encrypted_chunks(|chunks| {
+        for index in 0..chunked_text.chunks() {
+            chunks.remove(&ChunkKey::new(text_id.clone(), index));
+        }
+    });
+
+    Some(chunked_text)
+}
+
+/// Checks that the owner still has room for one more text, without storing anything.
+pub fn can_add_text_id(owner: &NoteOwner) -> Result<(), String> {
+    let text_id = Nonce::default();
+
+    match owner {
+        NoteOwner::Anonymous(public_key) => {
+            let public_key = vec_to_fixed_array(public_key)?;
+
+            with_anonymous_users(|users| {
+                users
+                    .get(&public_key)
+                    .unwrap_or_else(AnonymousUserData::new)
+                    .add_text_id(text_id)
+            })
+        }
+        NoteOwner::User(principal) => with_users(|users| {
+            users
+                .get(&principal.clone().into())
+                .unwrap_or_default()
+                .add_text_id(text_id)
+        }),
+    }
+}
+
+pub fn owner_has_text_id(owner: &NoteOwner, text_id: &Nonce) -> Result<bool, String> {
+    match owner {
+        NoteOwner::Anonymous(public_key) => {
+            let public_key = vec_to_fixed_array(public_key)?;
+
+            with_anonymous_user(&public_key, |user| Ok(user.has_text_id(text_id)))
+        }
+        NoteOwner::User(principal) => with_user(&principal.clone().into(), |user| {
+            Ok(user.has_text_id(text_id))
+        }),
+    }
+}
+
+/// Whether `reader` owns the note or was added as one of its recipients.
+pub fn can_read_note(reader: &NoteOwner, text_id: &Nonce) -> bool {
+    if owner_has_text_id(reader, text_id).unwrap_or(false) {
+        return true;
+    }
+
+    let identity = match reader {
+        NoteOwner::Anonymous(public_key) => RecipientIdentity::PublicKey(public_key.clone()),
+        NoteOwner::User(principal) => RecipientIdentity::Principal(*principal),
+    };
+
+    with_note_recipients(|recipients| recipients.get(text_id))
+        .is_some_and(|recipients| recipients.get(&identity).is_some())
+}
+
+pub fn add_text_id_to_owner(owner: &NoteOwner, text_id: Nonce) -> Result<(), String> {
+    match owner {
+        NoteOwner::Anonymous(public_key) => {
+            let public_key = vec_to_fixed_array(public_key)?;
+
+            with_anonymous_user_or_add(&public_key, |user| user.add_text_id(text_id))
+        }
+        NoteOwner::User(principal) => {
+            with_user_or_add(&principal.clone().into(), |user| user.add_text_id(text_id))
+        }
+    }
+}
+
+pub fn remove_text_id_from_owner(owner: &NoteOwner, text_id: &Nonce) -> Result<(), String> {
+    match owner {
+        NoteOwner::Anonymous(public_key) => {
+            let public_key = vec_to_fixed_array(public_key)?;
+
+            with_anonymous_users(|users| {
+                let mut user = users
+                    .get(&public_key)
+                    .ok_or("Error::Public key not found!".to_string())?;
+
+                user.remove_text_id(text_id)?;
+
+                users.insert(public_key, user);
+
+                Ok(())
+            })
+        }
+        NoteOwner::User(principal) => with_users(|users| {
+            let subaccount = principal.clone().into();
+
+            let mut user = users
+                .get(&subaccount)
+                .ok_or("Error::User not found!".to_string())?;
+
+            user.remove_text_id(text_id)?;
+
+            users.insert(subaccount, user);
+
+            Ok(())
+        }),
+    }
+}
+
+/// Frees everything stored for a note: its ciphertext or chunks, metadata and pending share key.
+///
+/// The owner's reference to the note is left to the caller.
+pub fn delete_note(text_id: &Nonce) {
+    with_encrypted_texts(|texts| texts.remove(text_id));
+
+    remove_chunked_text(text_id);
+
+    with_note_metas(|metas| metas.remove(text_id));
+
+    remove_one_time_key(text_id);
+
+    with_note_recipients(|recipients| recipients.remove(text_id));
+
+    with_note_access_logs(|logs| logs.remove(text_id));
+
+    remove_note_share_links(text_id);
+}
+
+/// Removes an anonymous user along with their notes and tombstones.
+pub fn remove_anonymous_user(public_key: &PublicKey) -> Option<AnonymousUserData> {
+    let user = with_anonymous_users(|users| users.remove(public_key));
+
+    // the notes of a removed user can never be read or shared again
+    if let Some(user) = &user {
+        user.iter_texts().for_each(delete_note);
+    }
+
+    remove_owner_tombstones(&NoteOwner::Anonymous(public_key.to_vec()));
+
+    user
+}
+
+pub fn with_note_access_logs<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<Nonce, NoteAccessLog>) -> R,
+{
+    NOTE_ACCESS_LOGS.with(|logs| f(&mut *logs.borrow_mut()))
+}
+
+pub fn record_note_access(text_id: &Nonce, entry: AccessEntry) {
+    with_note_access_logs(|logs| {
+        let mut log = logs.get(text_id).unwrap_or_default();
+
+        log.record(entry);
+
+        logs.insert(text_id.clone(), log);
+    })
+}
+
+/// Deletes a burn-after-reading note and leaves a tombstone for its owner.
+pub fn burn_note(owner: NoteOwner, text_id: &Nonce, read_at: NanoTimeStamp) {
+    delete_note(text_id);
+
+    if let Err(e) = remove_text_id_from_owner(&owner, text_id) {
+        log_cycle!(
+            "Failed to remove burned note {:?} from owner: {}",
+            text_id,
+            e
+        );
+    }
+
+    with_tombstones(|tombstones| {
+        tombstones.insert(text_id.clone(), NoteTombstone::new(owner, read_at))
+    });
+}
+
+/// Burns a note after a shared read when it is set to, otherwise refreshes its share status.
+///
+/// Returns whether the note was burned.
+pub fn finish_shared_read(owner: NoteOwner, text_id: &Nonce, read_at: NanoTimeStamp) -> bool {
+    let burn_after_reading =
+        with_note_metas(|metas| metas.get(text_id)).is_some_and(|meta| meta.burn_after_reading());
+
+    if burn_after_reading {
+        burn_note(owner, text_id, read_at);
+    } else {
+        refresh_share_status(text_id);
+    }
+
+    burn_after_reading
+}
+
+pub fn with_tombstones<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<Nonce, NoteTombstone>) -> R,
+{
+    TOMBSTONES.with(|tombstones| f(&mut *tombstones.borrow_mut()))
+}
+
+pub fn get_owner_tombstones(owner: &NoteOwner) -> Vec<(Nonce, NoteTombstone)> {
+    with_tombstones(|tombstones| {
+        tombstones
+            .iter()
+            .filter(|(_, tombstone)| tombstone.is_owned_by(owner))
+            .collect()
+    })
+}
+
+pub fn remove_owner_tombstones(owner: &NoteOwner) {
+    let text_ids: Vec<Nonce> = get_owner_tombstones(owner)
+        .into_iter()
+        .map(|(text_id, _)| text_id)
+        .collect();
+
+    with_tombstones(|tombstones| {
+        text_ids.iter().for_each(|text_id| {
+            tombstones.remove(text_id);
+        })
+    });
+}
+
+pub fn get_user_texts<'a>(text_ids: impl Iterator<Item = &'a Nonce>) -> Vec<UserText> {
+    with_encrypted_texts(|texts| {
+        text_ids
+            .map(|text_id| UserText {
+                id: text_id.to_string(),
+                // chunked texts are streamed with `get_encrypted_text_chunk`
+                text: texts
+                    .get(text_id)
+                    .map(|text| text.clone())
+                    .unwrap_or_default(),
+            })
+            .collect()
+    })
+}
+
+pub fn with_note_metas<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<Nonce, NoteMeta>) -> R,
+{
+    NOTE_METAS.with(|note_metas| f(&mut *note_metas.borrow_mut()))
+}
+
+/// Updates the metadata of a note, if it has any; notes saved before metadata existed are skipped.
+pub fn with_note_meta<F>(text_id: &Nonce, f: F)
+where
+    F: FnOnce(&mut NoteMeta),
+{
+    with_note_metas(|note_metas| {
+        if let Some(mut note_meta) = note_metas.get(text_id) {
+            f(&mut note_meta);
+
+            note_metas.insert(text_id.clone(), note_meta);
+        }
+    })
+}
+
+/// Pages through `text_ids` in ascending order, which the cursor relies on;
+/// claimed notes are appended to an account after its newer ones.
+pub fn get_note_page<'a>(
+    owner: &NoteOwner,
+    text_ids: impl Iterator<Item = &'a Nonce>,
+    cursor: Option<Nonce>,
+    limit: usize,
+) -> NotePage {
+    let text_ids: BTreeSet<&Nonce> = text_ids.collect();
+
+    with_note_metas(|note_metas| {
+        let mut text_ids = text_ids
+            .into_iter()
+            .filter(|text_id| match &cursor {
+                Some(cursor) => *text_id > cursor,
+                None => true,
+            })
+            .map(|text_id| {
+                let note_meta = note_metas.get(text_id).unwrap_or_else(|| {
+                    let size = with_encrypted_texts(|texts| texts.get(text_id))
+                        .map_or(0, |text| text.clone().len() as u64);
+
+                    NoteMeta::untracked(owner, size)
+                });
+
+                (text_id.clone(), note_meta)
+            });
+
+        let notes: Vec<(Nonce, NoteMeta)> = text_ids.by_ref().take(limit).collect();
+
+        let next_cursor = match text_ids.next() {
+            Some(_) => notes.last().map(|(text_id, _)| text_id.clone()),
+            None => None,
+        };
+
+        NotePage { notes, next_cursor }
+    })
+}
+
+pub fn with_roles<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<Subaccount, RoleGrant>) -> R,
+{
+    ROLES.with(|roles| f(&mut *roles.borrow_mut()))
+}
+
+pub fn get_role_grant(principal: &Principal) -> Option<RoleGrant> {
+    with_roles(|roles| roles.get(&principal.clone().into()))
+}
+
+pub fn with_note_recipients<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<Nonce, NoteRecipients>) -> R,
+{
+    NOTE_RECIPIENTS.with(|note_recipients| f(&mut *note_recipients.borrow_mut()))
+}
+
+/// Marks a note as shared while it has a pending one time key, share link or any recipient.
+pub fn refresh_share_status(text_id: &Nonce) {
+    let shared = with_one_time_keys(|keys| keys.contains_key(text_id))
+        || !get_note_share_links(text_id).is_empty()
+        || with_note_recipients(|recipients| {
+            recipients
+                .get(text_id)
+                .is_some_and(|recipients| !recipients.is_empty())
+        });
+
+    with_note_meta(text_id, |meta| {
+        meta.set_share_status(if shared {
+            ShareStatus::Shared
+        } else {
+            ShareStatus::Private
+        })
+    });
+}
+
+pub fn with_notifications<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<Nonce, Notification>) -> R,
+{
+    NOTIFICATIONS.with(|notifications| f(&mut *notifications.borrow_mut()))
+}
+
+pub fn with_notification<F, R>(id: &Nonce, f: F) -> Result<R, String>
+where
+    F: FnOnce(&mut Notification) -> R,
+{
+    with_notifications(|notifications| {
+        let mut notification = notifications
+            .get(id)
+            .ok_or("Error::Notification not found!".to_string())?;
+
+        let result = f(&mut notification);
+
+        notifications.insert(id.clone(), notification);
+
+        Ok(result)
+    })
+}
+
+pub fn with_dead_tasks<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<u64, DeadTask>) -> R,
+{
+    DEAD_TASKS.with(|dead_tasks| f(&mut *dead_tasks.borrow_mut()))
+}
+
+pub fn push_dead_task(dead_task: DeadTask) -> u64 {
+    with_dead_tasks(|dead_tasks| {
+        let id = dead_tasks
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or(0);
+
+        dead_tasks.insert(id, dead_task);
+
+        id
+    })
+}
+
+pub fn with_share_links<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<Nonce, ShareLink>) -> R,
+{
+    SHARE_LINKS.with(|share_links| f(&mut *share_links.borrow_mut()))
+}
+
+pub fn increment_share_link_id() -> Result<Nonce, String> {
+    SHARE_LINK_COUNTER.with(|nonce| {
+        let mut nonce = nonce.borrow_mut();
+
+        let current = nonce.get().add(Nonce::from(1));
+
+        let next = nonce
+            .set(current)
+            .map_err(|_| "Error::Share link counter overflowed!")?;
+
+        Ok(next)
+    })
+}
+
+pub fn insert_share_link(share_link: ShareLink) -> Result<Nonce, String> {
+    if get_note_share_links(share_link.text_id()).len() >= SHARE_LINKS_PER_NOTE {
+        return Err(format!(
+            "Error::Maximum of {} share links are allowed per note!",
+            SHARE_LINKS_PER_NOTE
+        ));
+    }
+
+    let link_id = increment_share_link_id()?;
+
+    with_share_links(|share_links| share_links.insert(link_id.clone(), share_link));
+
+    Ok(link_id)
+}
+
+pub fn with_share_link<F, R>(link_id: &Nonce, f: F) -> Result<R, String>
+where
+    F: FnOnce(&mut ShareLink) -> R,
+{
+    with_share_links(|share_links| {
+        let mut share_link = share_links
+            .get(link_id)
+            .ok_or("Error::Share link not found!".to_string())?;
+
+        let result = f(&mut share_link);
+
+        share_links.insert(link_id.clone(), share_link);
+
+        Ok(result)
+    })
+}
+
+pub fn get_note_share_links(text_id: &Nonce) -> Vec<(Nonce, ShareLink)> {
+    with_share_links(|share_links| {
+        share_links
+            .iter()
+            .filter(|(_, share_link)| share_link.text_id() == text_id)
+            .collect()
+    })
+}
+
+pub fn remove_note_share_links(text_id: &Nonce) {
+    let link_ids: Vec<Nonce> = get_note_share_links(text_id)
+        .into_iter()
+        .map(|(link_id, _)| link_id)
+        .collect();
+
+    with_share_links(|share_links| {
+        link_ids.iter().for_each(|link_id| {
+            share_links.remove(link_id);
+        })
+    });
+}
+
+pub fn with_claimed_identities<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<PublicKey, Subaccount>) -> R,
+{
+    CLAIMED_IDENTITIES.with(|claimed_identities| f(&mut *claimed_identities.borrow_mut()))
+}
+
+/// Whether `principal` claimed the anonymous identity of `public_key`.
+pub fn has_claimed_identity(public_key: &PublicKey, principal: Principal) -> bool {
+    with_claimed_identities(|claimed_identities| claimed_identities.get(public_key))
+        .is_some_and(|subaccount| subaccount == Subaccount::from(principal))
+}
+
+/// Moves the notes of an anonymous user into the account of `principal`,
+/// along with their tombstones.
+///
+/// One time keys and share links the anonymous identity handed out are
+/// revoked, readers may hold its key pair, so the account shares again.
+///
+/// Nothing is stored unless all of the notes fit the account. The claim is
+/// remembered, so the account can still get the keys of the anonymous
+/// identity its notes were encrypted to.
+pub fn claim_anonymous_notes(
+    public_key: &PublicKey,
+    principal: Principal,
+) -> Result<Vec<Nonce>, String> {
+    let anonymous_user = get_anonymous_user(public_key)?;
+
+    let text_ids: Vec<Nonce> = anonymous_user.iter_texts().cloned().collect();
+
+    let subaccount: Subaccount = principal.into();
+
+    let mut user = with_users(|users| users.get(&subaccount)).unwrap_or_default();
+
+    user.add_text_ids(&text_ids)?;
+
+    with_users(|users| users.insert(subaccount.clone(), user));
+
+    let from = NoteOwner::Anonymous(public_key.to_vec());
+    let to = NoteOwner::User(principal);
+
+    text_ids.iter().for_each(|text_id| {
+        with_note_meta(text_id, |meta| meta.set_owner(&to));
+
+        remove_one_time_key(text_id);
+        remove_note_share_links(text_id);
+        refresh_share_status(text_id);
+    });
+
+    with_tombstones(|tombstones| {
+        let text_ids: Vec<Nonce> = tombstones
+            .iter()
+            .filter(|(_, tombstone)| tombstone.is_owned_by(&from))
+            .map(|(text_id, _)| text_id)
+            .collect();
+
+        text_ids.into_iter().for_each(|text_id| {
+            if let Some(mut tombstone) = tombstones.get(&text_id) {
+                tombstone.set_owner(to.clone());
+
+                tombstones.insert(text_id, tombstone);
+            }
+        });
+    });
+
+    with_anonymous_users(|users| users.remove(public_key));
+
+    with_claimed_identities(|claimed_identities| {
+        claimed_identities.insert(*public_key, subaccount)
+    });
+
+    Ok(text_ids)
+}
+
+pub fn with_key_cache<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut DefaultVMMap<[u8; 32], CachedKey>) -> R,
+{
+    KEY_CACHE.with(|key_cache| f(&mut *key_cache.borrow_mut()))
+}
+
+pub fn get_cached_key(
+    cache_id: &[u8; 32],
+    scheme: KeyScheme,
+    now: &NanoTimeStamp,
+) -> Option<Vec<u8>> {
+    with_key_cache(|key_cache| key_cache.get(cache_id))
+        .and_then(|cached_key| cached_key.encrypted_key(scheme, now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::key_cache_id;
+
+    fn nonces(ids: impl IntoIterator<Item = u64>) -> Vec<Nonce> {
+        ids.into_iter().map(Nonce::from).collect()
+    }
+
+    fn page_ids(page: &NotePage) -> Vec<Nonce> {
+        page.notes
+            .iter()
+            .map(|(text_id, _)| text_id.clone())
+            .collect()
+    }
+
+    fn user() -> NoteOwner {
+        NoteOwner::User(Principal::from_slice(&[1; 29]))
+    }
+
+    /// Stores a note with the records `delete_note` is expected to free.
+    fn store_note(owner: &NoteOwner, text_id: u64) -> Nonce {
+        let text_id = Nonce::from(text_id);
+
+        with_encrypted_texts(|texts| texts.insert(text_id.clone(), EncryptedText::new(vec![7; 3])));
+        with_note_metas(|metas| metas.insert(text_id.clone(), NoteMeta::untracked(owner, 3)));
+        with_one_time_keys(|keys| keys.insert(text_id.clone(), OneTimeKey::default()));
+        with_note_recipients(|recipients| {
+            recipients.insert(text_id.clone(), NoteRecipients::default())
+        });
+        with_note_access_logs(|logs| logs.insert(text_id.clone(), NoteAccessLog::default()));
+
+        add_text_id_to_owner(owner, text_id.clone()).unwrap();
+
+        text_id
+    }
+
+    fn is_stored(text_id: &Nonce) -> bool {
+        with_encrypted_texts(|texts| texts.contains_key(text_id))
+            || with_note_metas(|metas| metas.contains_key(text_id))
+            || with_one_time_keys(|keys| keys.contains_key(text_id))
+            || with_note_recipients(|recipients| recipients.contains_key(text_id))
+            || with_note_access_logs(|logs| logs.contains_key(text_id))
+    }
+
+    #[test]
+    fn deleting_a_note_frees_everything_stored_for_it() {
+        let owner = user();
+
+        let deleted = store_note(&owner, 1);
+        let kept = store_note(&owner, 2);
+
+        remove_text_id_from_owner(&owner, &deleted).unwrap();
+        delete_note(&deleted);
+
+        assert!(!owner_has_text_id(&owner, &deleted).unwrap());
+        assert!(!is_stored(&deleted));
+
+        assert!(owner_has_text_id(&owner, &kept).unwrap());
+        assert!(is_stored(&kept));
+    }
+
+    #[test]
+    fn removing_an_anonymous_user_frees_their_notes() {
+        let public_key = [3; 48];
+        let owner = NoteOwner::Anonymous(public_key.to_vec());
+
+        with_anonymous_users(|users| users.insert(public_key, AnonymousUserData::default()));
+
+        let text_ids = [store_note(&owner, 1), store_note(&owner, 2)];
+        let other = store_note(&user(), 3);
+
+        let removed = remove_anonymous_user(&public_key).unwrap();
+
+        assert_eq!(removed.iter_texts().count(), 2);
+        assert!(with_anonymous_users(
+            |users| !users.contains_key(&public_key)
+        ));
+        assert!(text_ids.iter().all(|text_id| !is_stored(text_id)));
+        assert!(is_stored(&other));
+
+        assert!(remove_anonymous_user(&public_key).is_none());
+    }
+
+    #[test]
+    fn only_owners_and_recipients_can_read_a_note() {
+        let owner = user();
+        let text_id = store_note(&owner, 1);
+
+        let principal = Principal::from_slice(&[2; 29]);
+        let public_key = vec![3; 48];
+
+        with_note_recipients(|recipients| {
+            let mut note_recipients = NoteRecipients::default();
+            note_recipients
+                .add(
+                    RecipientIdentity::Principal(principal),
+                    vec![1],
+                    NanoTimeStamp(0),
+                )
+                .unwrap();
+            note_recipients
+                .add(
+                    RecipientIdentity::PublicKey(public_key.clone()),
+                    vec![1],
+                    NanoTimeStamp(0),
+                )
+                .unwrap();
+
+            recipients.insert(text_id.clone(), note_recipients)
+        });
+
+        assert!(can_read_note(&owner, &text_id));
+        assert!(can_read_note(&NoteOwner::User(principal), &text_id));
+        assert!(can_read_note(&NoteOwner::Anonymous(public_key), &text_id));
+
+        assert!(!can_read_note(
+            &NoteOwner::User(Principal::from_slice(&[4; 29])),
+            &text_id
+        ));
+        assert!(!can_read_note(&NoteOwner::Anonymous(vec![4; 48]), &text_id));
+        assert!(!can_read_note(&owner, &Nonce::from(2)));
+    }
+
+    #[test]
+    fn challenge_counters_outlive_their_user() {
+        let public_key = [2; 48];
+
+        assert_eq!(get_challenge_counter(&public_key), 0);
+
+        with_anonymous_users(|users| users.insert(public_key, AnonymousUserData::default()));
+
+        increment_challenge_counter(&public_key);
+        increment_challenge_counter(&public_key);
+
+        with_anonymous_users(|users| users.remove(&public_key));
+
+        assert_eq!(get_challenge_counter(&public_key), 2);
+    }
+
+    #[test]
+    fn note_pages_follow_the_cursor() {
+        let text_ids = nonces(1..=5);
+
+        let page = get_note_page(&user(), text_ids.iter(), None, 2);
+
+        assert_eq!(page_ids(&page), nonces([1, 2]));
+        assert_eq!(page.next_cursor, Some(Nonce::from(2)));
+
+        let page = get_note_page(&user(), text_ids.iter(), page.next_cursor, 2);
+
+        assert_eq!(page_ids(&page), nonces([3, 4]));
+        assert_eq!(page.next_cursor, Some(Nonce::from(4)));
+
+        let page = get_note_page(&user(), text_ids.iter(), page.next_cursor, 2);
+
+        assert_eq!(page_ids(&page), nonces([5]));
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn full_last_page_has_no_cursor() {
+        let text_ids = nonces(1..=4);
+
+        let page = get_note_page(&user(), text_ids.iter(), Some(Nonce::from(2)), 2);
+
+        assert_eq!(page_ids(&page), nonces([3, 4]));
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn note_pages_cover_claimed_notes_out_of_order() {
+        let public_key = [5; 48];
+        let principal = Principal::from_slice(&[5; 29]);
+        let owner = NoteOwner::User(principal);
+
+        store_note(&owner, 5);
+        store_note(&owner, 6);
+        anonymous_with_notes(public_key, [1, 2]);
+
+        claim_anonymous_notes(&public_key, principal).unwrap();
+
+        let text_ids: Vec<Nonce> = with_users(|users| users.get(&principal.into()))
+            .unwrap()
+            .iter_texts()
+            .cloned()
+            .collect();
+        assert_eq!(text_ids, nonces([5, 6, 1, 2]));
+
+        let page = get_note_page(&owner, text_ids.iter(), None, 2);
+
+        assert_eq!(page_ids(&page), nonces([1, 2]));
+
+        let page = get_note_page(&owner, text_ids.iter(), page.next_cursor, 2);
+
+        assert_eq!(page_ids(&page), nonces([5, 6]));
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn cursor_past_the_last_note_gives_an_empty_page() {
+        let text_ids = nonces(1..=4);
+
+        let page = get_note_page(&user(), text_ids.iter(), Some(Nonce::from(4)), 2);
+
+        assert!(page.notes.is_empty());
+        assert_eq!(page.next_cursor, None);
+
+        let page = get_note_page(&user(), nonces([]).iter(), None, 2);
+
+        assert!(page.notes.is_empty());
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn one_time_keys_run_out_of_tries() {
+        let text_id = Nonce::from(70);
+        with_one_time_keys(|keys| keys.insert(text_id.clone(), OneTimeKey::default()));
+
+        for _ in 0..get_config().one_time_key_max_tries {
+            let key = with_one_time_key_and_try(&text_id, |key| Ok(key.clone())).unwrap();
+            assert!(!key.out_of_tries());
+        }
+
+        let key = with_one_time_key_and_try(&text_id, |key| Ok(key.clone())).unwrap();
+        assert!(key.out_of_tries());
+
+        remove_one_time_key(&text_id);
+        assert!(with_one_time_key_and_try(&text_id, |key| Ok(key.clone())).is_err());
+    }
+
+    #[test]
+    fn dead_tasks_are_numbered_in_order() {
+        let dead_task = |error: &str| {
+            DeadTask::new(
+                Task::CleanUpKeys,
+                1,
+                error.to_string(),
+                NanoTimeStamp::default(),
+            )
+        };
+
+        let first = push_dead_task(dead_task("first"));
+        let second = push_dead_task(dead_task("second"));
+        assert_eq!(second, first + 1);
+
+        // retrying takes a task off the list, new ones never reuse a live id
+        with_dead_tasks(|dead_tasks| dead_tasks.remove(&first)).unwrap();
+        assert_eq!(push_dead_task(dead_task("third")), second + 1);
+
+        let ids: Vec<u64> =
+            with_dead_tasks(|dead_tasks| dead_tasks.iter().map(|(id, _)| id).collect());
+        assert_eq!(ids, [second, second + 1]);
+    }
+
+    #[test]
+    fn task_payloads_are_stored_out_of_line() {
+        let task = Task::SendText {
+            phone_number: "+15550100".to_string(),
+            body: "b".repeat(1024),
+        };
+
+        let id = insert_task(task.clone(), NanoTimeStamp(42)).unwrap();
+
+        let scheduled = with_tasks(|tasks| tasks.get(&id)).unwrap();
+        assert_eq!(scheduled.task(), &task);
+        assert!(scheduled.is_due_at(&NanoTimeStamp(42)));
+
+        // the timer heap only holds the id, which keeps its fixed size
+        assert_eq!(find_task(&task), Some(id));
+    }
+
+    #[test]
+    fn cancelled_tasks_are_skipped() {
+        let id = insert_task(Task::CleanUpKeys, NanoTimeStamp(10)).unwrap();
+
+        assert!(remove_task(&id).is_ok());
+        assert!(remove_task(&id).is_err());
+
+        // the heap entry still fires but finds nothing to run
+        assert!(take_due_task(&id, &NanoTimeStamp(10)).is_none());
+        assert_eq!(find_task(&Task::CleanUpKeys), None);
+    }
+
+    #[test]
+    fn requeued_tasks_run_once_at_their_new_time() {
+        let id = insert_task(Task::CleanUpAnonymousUsers, NanoTimeStamp(10)).unwrap();
+
+        // run now moves the task, leaving its old heap entry stale
+        with_scheduled_task(&id, |scheduled| scheduled.set_due_at(NanoTimeStamp(5))).unwrap();
+
+        assert!(take_due_task(&id, &NanoTimeStamp(10)).is_none());
+
+        let scheduled = take_due_task(&id, &NanoTimeStamp(5)).unwrap();
+        assert_eq!(scheduled.task(), &Task::CleanUpAnonymousUsers);
+
+        // running takes the payload out, so it can't run twice
+        assert!(take_due_task(&id, &NanoTimeStamp(5)).is_none());
+        assert!(with_scheduled_task(&id, |_| ()).is_err());
+    }
+
+    #[test]
+    fn burn_after_reading_notes_burn_on_the_first_read() {
+        let owner = user();
+        let text_id = store_note(&owner, 1);
+
+        with_note_meta(&text_id, |meta| meta.set_burn_after_reading(true));
+
+        let (_, wrapped_key) = take_one_time_key(&text_id).unwrap();
+        assert_eq!(wrapped_key, None);
+
+        assert!(finish_shared_read(
+            owner.clone(),
+            &text_id,
+            NanoTimeStamp(5)
+        ));
+
+        assert!(!is_stored(&text_id));
+        assert!(!owner_has_text_id(&owner, &text_id).unwrap());
+
+        let tombstones = get_owner_tombstones(&owner);
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(tombstones[0].0, text_id);
+
+        // a later read finds neither the key nor the note
+        assert!(take_one_time_key(&text_id).is_err());
+        assert!(with_encrypted_text(&text_id, |text| Ok(text.clone())).is_err());
+    }
+
+    #[test]
+    fn racing_one_time_reads_only_let_the_first_through() {
+        let owner = user();
+        let text_id = store_note(&owner, 1);
+
+        with_note_meta(&text_id, |meta| meta.set_burn_after_reading(true));
+        with_one_time_wrapped_keys(|wrapped_keys| {
+            wrapped_keys.insert(text_id.clone(), WrappedKey::new(vec![9; 32]).unwrap())
+        });
+
+        // both reads verified and waited for their encrypted key before either took the key
+        let first = take_one_time_key(&text_id);
+        let second = take_one_time_key(&text_id);
+
+        assert_eq!(first.unwrap().1, Some(vec![9; 32]));
+        assert_eq!(
+            second.unwrap_err(),
+            "Error::One time key was already used!".to_string()
+        );
+
+        assert!(finish_shared_read(
+            owner.clone(),
+            &text_id,
+            NanoTimeStamp(5)
+        ));
+        assert_eq!(get_owner_tombstones(&owner).len(), 1);
+    }
+
+    #[test]
+    fn kept_notes_go_private_after_their_one_time_read() {
+        let owner = user();
+        let text_id = store_note(&owner, 1);
+
+        with_note_meta(&text_id, |meta| meta.set_share_status(ShareStatus::Shared));
+
+        take_one_time_key(&text_id).unwrap();
+
+        assert!(!finish_shared_read(
+            owner.clone(),
+            &text_id,
+            NanoTimeStamp(5)
+        ));
+
+        let meta = with_note_metas(|metas| metas.get(&text_id)).unwrap();
+        assert_eq!(meta.share_status(), ShareStatus::Private);
+        assert!(owner_has_text_id(&owner, &text_id).unwrap());
+        assert!(get_owner_tombstones(&owner).is_empty());
+    }
+
+    fn access_outcomes(text_id: &Nonce) -> Vec<AccessOutcome> {
+        with_note_access_logs(|logs| logs.get(text_id))
+            .map_or(vec![], |log| log.into_inner())
+            .iter()
+            .map(AccessEntry::outcome)
+            .collect()
+    }
+
+    #[test]
+    fn one_time_reads_past_their_tries_are_logged() {
+        let text_id = store_note(&user(), 1);
+        let reader = RecipientIdentity::PublicKey(vec![2; 48]);
+
+        // the default key expires at 0, so read at 0
+        for _ in 0..get_config().one_time_key_max_tries {
+            assert!(try_one_time_key(&text_id, reader.clone(), NanoTimeStamp(0)).is_ok());
+        }
+
+        assert_eq!(
+            try_one_time_key(&text_id, reader, NanoTimeStamp(0)).unwrap_err(),
+            "Error::One time key is out of tries!".to_string()
+        );
+        assert_eq!(access_outcomes(&text_id), [AccessOutcome::OutOfTries]);
+    }
+
+    #[test]
+    fn expired_one_time_reads_are_logged() {
+        let text_id = store_note(&user(), 1);
+        let reader = RecipientIdentity::PublicKey(vec![2; 48]);
+
+        assert_eq!(
+            try_one_time_key(&text_id, reader, NanoTimeStamp(1)).unwrap_err(),
+            "Error::One time key is expired!".to_string()
+        );
+        assert_eq!(access_outcomes(&text_id), [AccessOutcome::Expired]);
+    }
+
+    fn share_link(text_id: u64) -> ShareLink {
+        owned_share_link(&user(), text_id)
+    }
+
+    fn owned_share_link(owner: &NoteOwner, text_id: u64) -> ShareLink {
+        let args = ShareLinkArgs {
+            public_key: vec![2; 48],
+            wrapped_key: None,
+            label: Some(format!("link for {}", text_id)),
+            max_reads: None,
+            expires_in_secs: None,
+        };
+
+        ShareLink::new(Nonce::from(text_id), owner.clone(), args, NanoTimeStamp(0)).unwrap()
+    }
+
+    #[test]
+    fn share_links_are_limited_per_note() {
+        for _ in 0..SHARE_LINKS_PER_NOTE {
+            insert_share_link(share_link(1)).unwrap();
+        }
+
+        assert!(insert_share_link(share_link(1)).is_err());
+        assert_eq!(
+            get_note_share_links(&Nonce::from(1)).len(),
+            SHARE_LINKS_PER_NOTE
+        );
+
+        // other notes have their own limit
+        let link_id = insert_share_link(share_link(2)).unwrap();
+        let links = get_note_share_links(&Nonce::from(2));
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].0, link_id);
+
+        remove_note_share_links(&Nonce::from(1));
+
+        assert!(get_note_share_links(&Nonce::from(1)).is_empty());
+        assert!(insert_share_link(share_link(1)).is_ok());
+    }
+
+    fn anonymous_with_notes(public_key: PublicKey, text_ids: [u64; 2]) -> NoteOwner {
+        let owner = NoteOwner::Anonymous(public_key.to_vec());
+
+        with_anonymous_users(|users| users.insert(public_key, AnonymousUserData::default()));
+
+        // shared the way anonymous owners share, with their own key and a link
+        text_ids.iter().for_each(|text_id| {
+            let text_id = store_note(&owner, *text_id);
+
+            with_one_time_keys(|keys| {
+                keys.insert(
+                    text_id.clone(),
+                    OneTimeKey::new(public_key, NanoTimeStamp(0)),
+                )
+            });
+
+            insert_share_link(owned_share_link(&owner, text_id.0)).unwrap();
+        });
+
+        owner
+    }
+
+    #[test]
+    fn claiming_moves_anonymous_notes_into_the_account() {
+        let public_key = [6; 48];
+        let principal = Principal::from_slice(&[6; 29]);
+        let from = anonymous_with_notes(public_key, [1, 2]);
+        let to = NoteOwner::User(principal);
+
+        assert_eq!(
+            claim_anonymous_notes(&public_key, principal).unwrap(),
+            nonces([1, 2])
+        );
+
+        // what the anonymous identity shared is revoked, not handed over
+        for text_id in nonces([1, 2]) {
+            assert!(owner_has_text_id(&to, &text_id).unwrap());
+            assert!(with_one_time_keys(|keys| keys.get(&text_id)).is_none());
+            assert!(get_note_share_links(&text_id).is_empty());
+            assert_eq!(
+                with_note_metas(|metas| metas.get(&text_id))
+                    .unwrap()
+                    .share_status(),
+                ShareStatus::Private
+            );
+        }
+
+        assert!(get_anonymous_user(&public_key).is_err());
+        assert!(owner_has_text_id(&from, &Nonce::from(1)).is_err());
+
+        // only the claiming account gets the keys the notes are still encrypted to
+        assert!(has_claimed_identity(&public_key, principal));
+        assert!(!has_claimed_identity(
+            &public_key,
+            Principal::from_slice(&[7; 29])
+        ));
+        assert!(!has_claimed_identity(&[7; 48], principal));
+    }
+
+    #[test]
+    fn failed_claims_store_nothing() {
+        let public_key = [6; 48];
+        let principal = Principal::from_slice(&[6; 29]);
+        let from = anonymous_with_notes(public_key, [1, 2]);
+
+        set_config(Config {
+            user_note_limit: 1,
+            ..Config::default()
+        })
+        .unwrap();
+
+        assert!(claim_anonymous_notes(&public_key, principal).is_err());
+
+        assert!(with_users(|users| users.get(&principal.into())).is_none());
+        assert!(!has_claimed_identity(&public_key, principal));
+        assert!(owner_has_text_id(&from, &Nonce::from(1)).unwrap());
+        assert_eq!(
+            with_one_time_keys(|keys| keys.get(&Nonce::from(1)))
+                .unwrap()
+                .owner(),
+            from
+        );
+        assert_eq!(get_note_share_links(&Nonce::from(1))[0].1.owner(), &from);
+
+        // unknown identities can't be claimed either
+        assert!(claim_anonymous_notes(&[7; 48], principal).is_err());
+        assert!(with_users(|users| users.get(&principal.into())).is_none());
+    }
+
+    #[test]
+    fn cached_keys_are_only_hit_with_the_same_scheme_and_transport_key() {
+        let derivation_id = [1; 29];
+        let transport_key = [2; 48];
+        let cache_id = key_cache_id(KeyScheme::Ibe, &derivation_id, &transport_key);
+        let now = NanoTimeStamp(0);
+
+        assert_eq!(get_cached_key(&cache_id, KeyScheme::Ibe, &now), None);
+
+        with_key_cache(|key_cache| {
+            key_cache.insert(
+                cache_id,
+                CachedKey::new(KeyScheme::Ibe, vec![3; 192], now.clone()),
+            )
+        });
+
+        assert_eq!(
+            get_cached_key(&cache_id, KeyScheme::Ibe, &now),
+            Some(vec![3; 192])
+        );
+        assert_eq!(get_cached_key(&cache_id, KeyScheme::Symmetric, &now), None);
+
+        // a new transport key can't read the old key, so it looks up another entry
+        let new_cache_id = key_cache_id(KeyScheme::Ibe, &derivation_id, &[4; 48]);
+        assert_eq!(get_cached_key(&new_cache_id, KeyScheme::Ibe, &now), None);
+    }
+
+    #[test]
+    fn cached_keys_miss_once_expired() {
+        let cache_id = key_cache_id(KeyScheme::Symmetric, &[1; 29], &[2; 48]);
+
+        with_key_cache(|key_cache| {
+            key_cache.insert(
+                cache_id,
+                CachedKey::new(KeyScheme::Symmetric, vec![3; 192], NanoTimeStamp(0)),
+            )
+        });
+
+        let expiration = NanoTimeStamp(0).add_secs(60 * 60);
+
+        assert!(get_cached_key(&cache_id, KeyScheme::Symmetric, &expiration).is_some());
+        assert!(get_cached_key(
+            &cache_id,
+            KeyScheme::Symmetric,
+            &NanoTimeStamp(expiration.0 + 1)
+        )
+        .is_none());
+    }
+}
@@ -3,17 +3,144 @@ use b3_utils::{
     nonce::Nonce,
     NanoTimeStamp,
 };
-use candid::CandidType;
-use ciborium::de::from_reader;
-use ciborium::ser::into_writer;
+use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
-use std::io::Cursor;
+
+use crate::{
+    migration::{decode, encode, Versioned, ENVELOPE_SIZE},
+    store::get_config,
+};
+
+const ANONYMOUS_PROOF_DOMAIN: &[u8] = b"b3note-anonymous-proof";
 
 const ONE_TIME_KEY_EXPIRATION: u64 = 60 * 60 * 24 * 1; // 1 days
 const ANONYMOUS_USER_DATA_EXPIRATION: u64 = 60 * 60 * 24 * 1; // 1 days
+const CLEAN_UP_INTERVAL: u64 = 60 * 60; // 1 hour
+const CHUNKED_UPLOAD_EXPIRATION: u64 = 60 * 60; // 1 hour
+const CACHED_KEY_EXPIRATION: u64 = 60 * 60; // 1 hour
+
+// note ids live in `UserData` and `AnonymousUserData`, whose records can't grow past 500 bytes
+const NOTE_LIMIT_MAX: u64 = 32;
+
+const NOTE_PAGE_DEFAULT_LIMIT: usize = 20;
+const NOTE_PAGE_MAX_LIMIT: usize = 100;
+
+const SIMPLE_NOTES_PER_KEY: usize = 10;
+const SIMPLE_NOTE_MAX_SIZE: usize = 256;
+
+const NOTE_RECIPIENTS_MAX: usize = 10;
+const NOTE_ACCESS_LOG_MAX: usize = 20;
+
+pub const SHARE_LINKS_PER_NOTE: usize = 10;
+const SHARE_LINK_LABEL_MAX_SIZE: usize = 64;
+const SHARE_LINK_MAX_EXPIRATION: u64 = 60 * 60 * 24 * 30; // 30 days
+const WRAPPED_KEY_MAX_SIZE: usize = 256;
+
+const NOTIFICATION_BODY_MAX_SIZE: usize = 2048;
+
+const TASK_MAX_ATTEMPTS: u8 = 8;
+const TASK_RETRY_BACKOFF: u64 = 10; // 10 seconds
+
+const ERROR_MAX_SIZE: usize = 256;
+const MAX_BACKOFF: u64 = 60 * 60; // 1 hour
+
+const ENCRYPTED_CHUNK_MAX_SIZE: u32 = 16 * 1024; // 16 KiB
+const CHUNKED_TEXT_MAX_SIZE: u64 = 10 * 1024 * 1024; // 10 MiB
 
 pub type PublicKey = [u8; 48];
 
+/// Doubles `base` for every attempt after the first, capped at an hour.
+pub fn exponential_backoff(base: u64, attempts: u8) -> u64 {
+    let factor = 1u64 << attempts.saturating_sub(1).min(16);
+
+    base.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+/// Cuts an error message down so it fits in a bounded record.
+fn truncate_error(mut error: String) -> String {
+    if error.len() > ERROR_MAX_SIZE {
+        let mut end = ERROR_MAX_SIZE;
+
+        while !error.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        error.truncate(end);
+    }
+
+    error
+}
+
+/// Quotas and expirations, set through the init/upgrade args or `update_config`.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Config {
+    pub one_time_key_expiration_secs: u64,
+    pub one_time_key_max_tries: u8,
+    pub anonymous_user_expiration_secs: u64,
+    pub anonymous_user_note_limit: u64,
+    pub user_note_limit: u64,
+    pub clean_up_interval_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            one_time_key_expiration_secs: ONE_TIME_KEY_EXPIRATION,
+            one_time_key_max_tries: 3,
+            anonymous_user_expiration_secs: ANONYMOUS_USER_DATA_EXPIRATION,
+            anonymous_user_note_limit: 5,
+            user_note_limit: 10,
+            clean_up_interval_secs: CLEAN_UP_INTERVAL,
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.one_time_key_max_tries == 0 {
+            return Err("Error::One time keys need at least one try!".to_string());
+        }
+
+        if self.anonymous_user_note_limit == 0 || self.user_note_limit == 0 {
+            return Err("Error::Note limits must be greater than zero!".to_string());
+        }
+
+        if self.anonymous_user_note_limit > NOTE_LIMIT_MAX || self.user_note_limit > NOTE_LIMIT_MAX
+        {
+            return Err(format!(
+                "Error::Note limits can't be greater than {}!",
+                NOTE_LIMIT_MAX
+            ));
+        }
+
+        // cleaning up more often than this just burns cycles
+        if self.clean_up_interval_secs < 60 {
+            return Err("Error::Clean up interval must be at least 60 seconds!".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 300,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for Config {
+    const VERSION: u8 = 1;
+}
+
 pub type EncryptionKey = [u8; 96];
 
 #[derive(CandidType, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
@@ -58,6 +185,55 @@ pub struct UserText {
     pub text: Vec<u8>,
 }
 
+#[derive(Default, Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct SimpleNotes(Vec<String>);
+
+impl SimpleNotes {
+    pub fn add_note(&mut self, note: String) -> Result<usize, String> {
+        if note.len() > SIMPLE_NOTE_MAX_SIZE {
+            return Err(format!(
+                "Error::Note is larger than {} bytes!",
+                SIMPLE_NOTE_MAX_SIZE
+            ));
+        }
+
+        if self.0.len() >= SIMPLE_NOTES_PER_KEY {
+            return Err(format!(
+                "Error::Maximum of {} notes are allowed!",
+                SIMPLE_NOTES_PER_KEY
+            ));
+        }
+
+        self.0.push(note);
+
+        Ok(self.0.len())
+    }
+
+    pub fn into_inner(self) -> Vec<String> {
+        self.0
+    }
+}
+
+impl Storable for SimpleNotes {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    // every note is a text header of at most 3 bytes plus its content
+    const BOUND: Bound = Bound::Bounded {
+        max_size: ENVELOPE_SIZE + (SIMPLE_NOTES_PER_KEY * (SIMPLE_NOTE_MAX_SIZE + 3) + 9) as u32,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for SimpleNotes {
+    const VERSION: u8 = 1;
+}
+
 #[derive(candid::CandidType, Clone, Deserialize)]
 pub struct EncryptedText(Vec<u8>);
 
@@ -86,237 +262,1474 @@ impl Storable for EncryptedText {
     };
 }
 
-#[derive(Default, Debug, Serialize, Clone, CandidType, Deserialize)]
-pub struct OneTimeKey {
-    time_lock: NanoTimeStamp,
-    public_key: Vec<u8>,
-    tries: u8,
+/// Roles are ordered by privilege, an `Admin` can do everything an `Auditor` can.
+#[derive(
+    Debug, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize,
+)]
+pub enum Role {
+    Auditor,
+    Admin,
 }
 
-impl OneTimeKey {
-    pub fn new(public_key: PublicKey) -> Self {
-        let public_key = public_key.to_vec();
+#[derive(Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct RoleGrant {
+    principal: Principal,
+    role: Role,
+    granted_by: Principal,
+    granted_at: NanoTimeStamp,
+}
 
-        Self {
-            time_lock: NanoTimeStamp::now().add_secs(ONE_TIME_KEY_EXPIRATION),
-            public_key,
-            tries: 0,
-        }
+impl Storable for RoleGrant {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
     }
 
-    pub fn out_of_tries(&self) -> bool {
-        self.tries >= 3
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
     }
 
-    pub fn add_try(&mut self) {
-        self.tries += 1;
-    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 200,
+        is_fixed_size: false,
+    };
+}
 
-    pub fn is_expired(&self) -> bool {
-        self.time_lock.has_passed()
+impl Versioned for RoleGrant {
+    const VERSION: u8 = 1;
+}
+
+impl RoleGrant {
+    pub fn new(principal: Principal, role: Role, granted_by: Principal) -> Self {
+        Self {
+            principal,
+            role,
+            granted_by,
+            granted_at: NanoTimeStamp::now(),
+        }
     }
 
-    pub fn public_key(&self) -> &[u8] {
-        &self.public_key
+    pub fn has_role(&self, role: Role) -> bool {
+        self.role >= role
     }
 }
 
-impl Storable for OneTimeKey {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = vec![];
-        into_writer(&self, &mut bytes).unwrap();
-        std::borrow::Cow::Owned(bytes)
+/// Proves control of an anonymous user's transport key for a single call.
+///
+/// The signature is made with `TransportSecretKey::sign` over the challenge
+/// returned by `anonymous_challenge`, which binds the key, the user's current
+/// challenge counter, the method name and a hash of the call arguments.
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct AnonymousProof {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl AnonymousProof {
+    pub fn challenge(
+        public_key: &PublicKey,
+        counter: u64,
+        method: &str,
+        args_hash: &[u8],
+    ) -> Vec<u8> {
+        let mut challenge = Vec::with_capacity(
+            ANONYMOUS_PROOF_DOMAIN.len() + public_key.len() + 16 + method.len() + args_hash.len(),
+        );
+
+        challenge.extend_from_slice(ANONYMOUS_PROOF_DOMAIN);
+        challenge.extend_from_slice(public_key);
+        challenge.extend_from_slice(&counter.to_be_bytes());
+        challenge.extend_from_slice(&(method.len() as u64).to_be_bytes());
+        challenge.extend_from_slice(method.as_bytes());
+        challenge.extend_from_slice(args_hash);
+
+        challenge
     }
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        from_reader(&mut Cursor::new(&bytes)).unwrap()
+#[derive(Debug, Serialize, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub enum NoteOwner {
+    Anonymous(Vec<u8>),
+    User(Principal),
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum OwnerKind {
+    Anonymous,
+    User,
+}
+
+impl From<&NoteOwner> for OwnerKind {
+    fn from(owner: &NoteOwner) -> Self {
+        match owner {
+            NoteOwner::Anonymous(_) => OwnerKind::Anonymous,
+            NoteOwner::User(_) => OwnerKind::User,
+        }
     }
+}
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 200,
-        is_fixed_size: false,
-    };
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum EncryptionScheme {
+    Ibe,
+    Symmetric,
+    /// IBE to the time-lock key of the epoch, in seconds, it unlocks at.
+    TimeLock(u64),
 }
 
-#[derive(Default, Debug, Serialize, Clone, CandidType, Deserialize)]
-pub struct AnonymousUserData {
-    texts: Vec<Nonce>,
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ShareStatus {
+    Private,
+    Shared,
+}
+
+#[derive(Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct NoteMeta {
     created_at: NanoTimeStamp,
-    decryption_key: Option<Vec<u8>>,
+    updated_at: NanoTimeStamp,
+    size: u64,
+    chunked: bool,
+    scheme: Option<EncryptionScheme>,
+    owner_kind: OwnerKind,
+    share_status: ShareStatus,
+    #[serde(default)]
+    burn_after_reading: bool,
 }
 
-impl Storable for AnonymousUserData {
+impl Storable for NoteMeta {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = vec![];
-        into_writer(&self, &mut bytes).unwrap();
-        std::borrow::Cow::Owned(bytes)
+        std::borrow::Cow::Owned(encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        from_reader(&mut Cursor::new(&bytes)).unwrap()
+        decode(&bytes).unwrap()
     }
 
     const BOUND: Bound = Bound::Bounded {
-        max_size: 500,
+        max_size: 200,
         is_fixed_size: false,
     };
 }
 
-impl AnonymousUserData {
-    pub fn new(decryption_key: Option<Vec<u8>>) -> Self {
+impl Versioned for NoteMeta {
+    const VERSION: u8 = 1;
+}
+
+impl NoteMeta {
+    pub fn new(owner: &NoteOwner, size: u64, scheme: Option<EncryptionScheme>) -> Self {
+        let now = NanoTimeStamp::now();
+
         Self {
-            texts: vec![],
-            created_at: NanoTimeStamp::now(),
-            decryption_key,
+            created_at: now.clone(),
+            updated_at: now,
+            size,
+            chunked: false,
+            scheme,
+            owner_kind: owner.into(),
+            share_status: ShareStatus::Private,
+            burn_after_reading: false,
         }
     }
 
-    pub fn set_decryption_key(&mut self, key: Vec<u8>) {
-        self.decryption_key = Some(key);
+    /// Metadata for notes saved before metadata was recorded; timestamps are unknown.
+    pub fn untracked(owner: &NoteOwner, size: u64) -> Self {
+        Self {
+            created_at: NanoTimeStamp::default(),
+            updated_at: NanoTimeStamp::default(),
+            size,
+            chunked: false,
+            scheme: None,
+            owner_kind: owner.into(),
+            share_status: ShareStatus::Private,
+            burn_after_reading: false,
+        }
     }
 
-    pub fn is_expired(&self) -> bool {
-        self.created_at.elapsed().to_secs() > ANONYMOUS_USER_DATA_EXPIRATION
+    pub fn new_chunked(owner: &NoteOwner, size: u64, scheme: Option<EncryptionScheme>) -> Self {
+        Self {
+            chunked: true,
+            ..Self::new(owner, size, scheme)
+        }
     }
 
-    pub fn has_text_id(&self, text_id: &Nonce) -> bool {
-        self.texts.contains(text_id)
-    }
+    /// Records an edit that replaced the content with a single blob of `size` bytes.
+    pub fn update(&mut self, size: u64, scheme: Option<EncryptionScheme>) {
+        self.updated_at = NanoTimeStamp::now();
+        self.size = size;
+        self.chunked = false;
 
-    pub fn add_text_id(&mut self, text_id: Nonce) -> Result<(), &'static str> {
-        if self.texts.len() >= 5 {
-            return Err("Maximum of 5 text are allowed");
+        if scheme.is_some() {
+            self.scheme = scheme;
         }
+    }
 
-        self.texts.push(text_id);
+    #[cfg(test)]
+    pub fn share_status(&self) -> ShareStatus {
+        self.share_status
+    }
 
-        Ok(())
+    pub fn set_share_status(&mut self, share_status: ShareStatus) {
+        self.share_status = share_status;
     }
 
-    pub fn remove_text_id(&mut self, text_id: &Nonce) -> Result<(), &'static str> {
-        if self.texts.len() < 1 {
-            return Err("No text to remove");
-        }
+    pub fn burn_after_reading(&self) -> bool {
+        self.burn_after_reading
+    }
 
-        self.texts.retain(|id| id != text_id);
+    pub fn set_burn_after_reading(&mut self, burn_after_reading: bool) {
+        self.burn_after_reading = burn_after_reading;
+    }
 
-        Ok(())
+    pub fn set_owner(&mut self, owner: &NoteOwner) {
+        self.owner_kind = owner.into();
     }
+}
 
-    pub fn iter_texts(&self) -> impl Iterator<Item = &Nonce> {
-        self.texts.iter()
+/// Left behind when a burn-after-reading note is deleted by its first read.
+#[derive(Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct NoteTombstone {
+    owner: NoteOwner,
+    read_at: NanoTimeStamp,
+}
+
+impl Storable for NoteTombstone {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
     }
 
-    pub fn get_created_at(&self) -> NanoTimeStamp {
-        self.created_at.clone()
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 128,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for NoteTombstone {
+    const VERSION: u8 = 1;
+}
+
+impl NoteTombstone {
+    pub fn new(owner: NoteOwner, read_at: NanoTimeStamp) -> Self {
+        Self { owner, read_at }
+    }
+
+    pub fn is_owned_by(&self, owner: &NoteOwner) -> bool {
+        &self.owner == owner
+    }
+
+    pub fn set_owner(&mut self, owner: NoteOwner) {
+        self.owner = owner;
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum AccessOutcome {
+    Success,
+    BadSignature,
+    Expired,
+    OutOfTries,
+}
+
+#[derive(Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct AccessEntry {
+    reader: RecipientIdentity,
+    accessed_at: NanoTimeStamp,
+    outcome: AccessOutcome,
+}
+
+impl AccessEntry {
+    pub fn new(
+        caller: Principal,
+        public_key: Vec<u8>,
+        outcome: AccessOutcome,
+        accessed_at: NanoTimeStamp,
+    ) -> Self {
+        Self::for_reader(Self::reader(caller, public_key), outcome, accessed_at)
     }
 
-    pub fn get_decryption_key(&self) -> Result<Vec<u8>, String> {
-        if self.is_expired() {
-            return Err("Decryption key expired".to_string());
+    pub fn for_reader(
+        reader: RecipientIdentity,
+        outcome: AccessOutcome,
+        accessed_at: NanoTimeStamp,
+    ) -> Self {
+        Self {
+            reader,
+            accessed_at,
+            outcome,
         }
+    }
 
-        if let Some(decryption_key) = self.decryption_key.clone() {
-            if decryption_key.len() != 192 {
-                return Err("Decryption key is not Valid!".to_string());
-            }
+    /// Readers are recorded by principal, or by public key when anonymous.
+    pub fn reader(caller: Principal, mut public_key: Vec<u8>) -> RecipientIdentity {
+        if caller == Principal::anonymous() {
+            public_key.truncate(48);
 
-            return Ok(decryption_key);
+            RecipientIdentity::PublicKey(public_key)
         } else {
-            return Err("No decryption key found".to_string());
+            RecipientIdentity::Principal(caller)
         }
     }
-}
 
-#[derive(Serialize, Clone, CandidType, Deserialize)]
-pub struct AuthenticatedSignature {
-    pub signature: Vec<u8>,
-    pub created_at: NanoTimeStamp,
+    #[cfg(test)]
+    pub fn outcome(&self) -> AccessOutcome {
+        self.outcome
+    }
 }
 
-#[derive(Default, Serialize, Clone, CandidType, Deserialize)]
-pub struct UserData {
-    texts: Vec<Nonce>,
-    public_key: Vec<u8>,
-    signature: Option<AuthenticatedSignature>,
-}
+/// The latest reads of a note, the oldest entry is dropped once it is full.
+#[derive(Default, Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct NoteAccessLog(Vec<AccessEntry>);
 
-impl Storable for UserData {
+impl Storable for NoteAccessLog {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = vec![];
-        into_writer(&self, &mut bytes).unwrap();
-        std::borrow::Cow::Owned(bytes)
+        std::borrow::Cow::Owned(encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        from_reader(&mut Cursor::new(&bytes)).unwrap()
+        decode(&bytes).unwrap()
     }
 
     const BOUND: Bound = Bound::Bounded {
-        max_size: 500,
+        max_size: 8192,
         is_fixed_size: false,
     };
 }
 
-impl UserData {
-    pub fn new(public_key: Vec<u8>, text_id: Option<Nonce>) -> Self {
-        Self {
-            texts: text_id.into_iter().collect(),
-            public_key,
-            signature: None,
-        }
-    }
+impl Versioned for NoteAccessLog {
+    const VERSION: u8 = 1;
+}
 
-    pub fn add_text_id(&mut self, text_id: Nonce) -> Result<(), &'static str> {
-        if self.texts.len() > 10 {
-            return Err("Maximum of 10 text are allowed");
+impl NoteAccessLog {
+    pub fn record(&mut self, entry: AccessEntry) {
+        if self.0.len() >= NOTE_ACCESS_LOG_MAX {
+            self.0.remove(0);
         }
 
-        self.texts.push(text_id);
+        self.0.push(entry);
+    }
 
-        Ok(())
+    pub fn into_inner(self) -> Vec<AccessEntry> {
+        self.0
     }
+}
 
-    pub fn remove_text_id(&mut self, text_id: &Nonce) -> Result<(), &'static str> {
-        if self.texts.len() < 1 {
-            return Err("No text to remove");
-        }
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub struct NotePage {
+    pub notes: Vec<(Nonce, NoteMeta)>,
+    pub next_cursor: Option<Nonce>,
+}
 
-        self.texts.retain(|id| id != text_id);
+impl NotePage {
+    /// Clamps a requested page size to something a single query can answer.
+    pub fn limit(limit: Option<u64>) -> usize {
+        limit
+            .map(|limit| (limit as usize).clamp(1, NOTE_PAGE_MAX_LIMIT))
+            .unwrap_or(NOTE_PAGE_DEFAULT_LIMIT)
+    }
+}
 
-        Ok(())
+/// Who a note is shared with; the identity doubles as the IBE derivation id.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub enum RecipientIdentity {
+    Principal(Principal),
+    PublicKey(Vec<u8>),
+}
+
+impl RecipientIdentity {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            RecipientIdentity::Principal(principal) if *principal == Principal::anonymous() => {
+                Err("Error::Cannot share with the anonymous principal!".to_string())
+            }
+            RecipientIdentity::PublicKey(public_key) if public_key.len() != 48 => Err(format!(
+                "Error::Expected public key length 48, found {}!",
+                public_key.len()
+            )),
+            _ => Ok(()),
+        }
     }
 
-    pub fn iter_texts(&self) -> impl Iterator<Item = &Nonce> {
-        self.texts.iter()
+    pub fn derivation_id(&self) -> Vec<u8> {
+        match self {
+            RecipientIdentity::Principal(principal) => principal.as_slice().to_vec(),
+            RecipientIdentity::PublicKey(public_key) => public_key.clone(),
+        }
     }
 }
 
-#[derive(CandidType, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
-pub enum Task {
-    Initialize,
-    CleanUpAnonymousUsers,
-    CleanUpKeys,
-    SendEmail {
-        email: String,
-        subject: String,
-        body: String,
-    },
-    SendText {
-        phone_number: String,
-        body: String,
-    },
+#[derive(Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct Recipient {
+    identity: RecipientIdentity,
+    wrapped_key: Vec<u8>,
+    added_at: NanoTimeStamp,
+}
+
+impl Recipient {
+    pub fn wrapped_key(&self) -> &[u8] {
+        &self.wrapped_key
+    }
 }
 
-impl Storable for Task {
+/// The recipients of a note, each holding the note's content key wrapped
+/// with `IBECiphertext::encrypt` to their own derivation id.
+#[derive(Default, Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct NoteRecipients(Vec<Recipient>);
+
+impl Storable for NoteRecipients {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = vec![];
-        into_writer(&self, &mut bytes).unwrap();
-        std::borrow::Cow::Owned(bytes)
+        std::borrow::Cow::Owned(encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        from_reader(&mut Cursor::new(&bytes)).unwrap()
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 8192,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for NoteRecipients {
+    const VERSION: u8 = 1;
+}
+
+impl NoteRecipients {
+    /// Adds a recipient, replacing the wrapped key of an existing one.
+    pub fn add(
+        &mut self,
+        identity: RecipientIdentity,
+        wrapped_key: Vec<u8>,
+        added_at: NanoTimeStamp,
+    ) -> Result<(), String> {
+        identity.validate()?;
+
+        if wrapped_key.is_empty() || wrapped_key.len() > WRAPPED_KEY_MAX_SIZE {
+            return Err(format!(
+                "Error::Wrapped key must be between 1 and {} bytes!",
+                WRAPPED_KEY_MAX_SIZE
+            ));
+        }
+
+        self.0.retain(|recipient| recipient.identity != identity);
+
+        if self.0.len() >= NOTE_RECIPIENTS_MAX {
+            return Err(format!(
+                "Error::Maximum of {} recipients are allowed!",
+                NOTE_RECIPIENTS_MAX
+            ));
+        }
+
+        self.0.push(Recipient {
+            identity,
+            wrapped_key,
+            added_at,
+        });
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, identity: &RecipientIdentity) -> Result<(), String> {
+        let len = self.0.len();
+
+        self.0.retain(|recipient| &recipient.identity != identity);
+
+        if self.0.len() == len {
+            return Err("Error::Recipient not found!".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, identity: &RecipientIdentity) -> Option<&Recipient> {
+        self.0
+            .iter()
+            .find(|recipient| &recipient.identity == identity)
+    }
+
+    pub fn identities(&self) -> Vec<RecipientIdentity> {
+        self.0
+            .iter()
+            .map(|recipient| recipient.identity.clone())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub struct RecipientKey {
+    pub wrapped_key: Vec<u8>,
+    pub encrypted_key: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChunkKey {
+    text_id: Nonce,
+    index: u32,
+}
+
+impl ChunkKey {
+    pub fn new(text_id: Nonce, index: u32) -> Self {
+        Self { text_id, index }
+    }
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.text_id.to_bytes().into_owned();
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (text_id, index) = bytes.split_at(bytes.len() - 4);
+
+        Self {
+            text_id: Nonce::from_bytes(std::borrow::Cow::Borrowed(text_id)),
+            index: u32::from_be_bytes(index.try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub struct EncryptedChunk(Vec<u8>);
+
+impl EncryptedChunk {
+    pub fn new(chunk: Vec<u8>) -> Result<Self, String> {
+        if chunk.is_empty() {
+            return Err("Error::Chunk is empty!".to_string());
+        }
+
+        if chunk.len() > ENCRYPTED_CHUNK_MAX_SIZE as usize {
+            return Err(format!(
+                "Error::Chunk is larger than {} bytes!",
+                ENCRYPTED_CHUNK_MAX_SIZE
+            ));
+        }
+
+        Ok(Self(chunk))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl Storable for EncryptedChunk {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        self.0.clone().into()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: ENCRYPTED_CHUNK_MAX_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub struct EncryptedTextChunk {
+    pub chunk: Vec<u8>,
+    pub next: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct ChunkedText {
+    owner: NoteOwner,
+    total_size: u64,
+    received: u64,
+    chunks: u32,
+    created_at: NanoTimeStamp,
+    committed: bool,
+}
+
+impl Storable for ChunkedText {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 300,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for ChunkedText {
+    const VERSION: u8 = 1;
+}
+
+impl ChunkedText {
+    pub fn new(owner: NoteOwner, total_size: u64) -> Result<Self, String> {
+        if total_size == 0 {
+            return Err("Error::Upload size must be greater than zero!".to_string());
+        }
+
+        if total_size > CHUNKED_TEXT_MAX_SIZE {
+            return Err(format!(
+                "Error::Upload is larger than {} bytes!",
+                CHUNKED_TEXT_MAX_SIZE
+            ));
+        }
+
+        Ok(Self {
+            owner,
+            total_size,
+            received: 0,
+            chunks: 0,
+            created_at: NanoTimeStamp::now(),
+            committed: false,
+        })
+    }
+
+    pub fn is_owned_by(&self, owner: &NoteOwner) -> bool {
+        &self.owner == owner
+    }
+
+    /// Reserves the next chunk index for a chunk of `len` bytes.
+    pub fn append(&mut self, len: usize) -> Result<u32, String> {
+        if self.committed {
+            return Err("Error::Upload is already committed!".to_string());
+        }
+
+        let received = self.received + len as u64;
+
+        if received > self.total_size {
+            return Err("Error::Chunk exceeds the declared upload size!".to_string());
+        }
+
+        let index = self.chunks;
+
+        self.received = received;
+        self.chunks += 1;
+
+        Ok(index)
+    }
+
+    pub fn commit(&mut self) -> Result<(), String> {
+        if self.committed {
+            return Err("Error::Upload is already committed!".to_string());
+        }
+
+        if self.received != self.total_size {
+            return Err(format!(
+                "Error::Upload is incomplete, received {} of {} bytes!",
+                self.received, self.total_size
+            ));
+        }
+
+        self.committed = true;
+
+        Ok(())
+    }
+
+    pub fn is_committed(&self) -> bool {
+        self.committed
+    }
+
+    /// Uncommitted uploads are abandoned after an hour.
+    pub fn is_expired(&self) -> bool {
+        !self.committed && self.created_at.elapsed().to_secs() > CHUNKED_UPLOAD_EXPIRATION
+    }
+
+    pub fn chunks(&self) -> u32 {
+        self.chunks
+    }
+
+    pub fn size(&self) -> u64 {
+        self.received
+    }
+}
+
+#[derive(Default, Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct OneTimeKey {
+    time_lock: NanoTimeStamp,
+    public_key: Vec<u8>,
+    tries: u8,
+    /// Set when an authenticated user shares with a separate link key,
+    /// anonymous owners share with their own public key.
+    #[serde(default)]
+    owner: Option<Principal>,
+}
+
+impl OneTimeKey {
+    pub fn new(public_key: PublicKey, now: NanoTimeStamp) -> Self {
+        let public_key = public_key.to_vec();
+
+        Self {
+            time_lock: now.add_secs(get_config().one_time_key_expiration_secs),
+            public_key,
+            tries: 0,
+            owner: None,
+        }
+    }
+
+    pub fn new_for_user(owner: Principal, link_public_key: PublicKey, now: NanoTimeStamp) -> Self {
+        Self {
+            owner: Some(owner),
+            ..Self::new(link_public_key, now)
+        }
+    }
+
+    pub fn owner(&self) -> NoteOwner {
+        match self.owner {
+            Some(principal) => NoteOwner::User(principal),
+            None => NoteOwner::Anonymous(self.public_key.clone()),
+        }
+    }
+
+    pub fn out_of_tries(&self) -> bool {
+        self.tries >= get_config().one_time_key_max_tries
+    }
+
+    pub fn add_try(&mut self) {
+        self.tries = self.tries.saturating_add(1);
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(&NanoTimeStamp::now())
+    }
+
+    pub fn is_expired_at(&self, now: &NanoTimeStamp) -> bool {
+        &self.time_lock < now
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+}
+
+impl Storable for OneTimeKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 200,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for OneTimeKey {
+    const VERSION: u8 = 1;
+}
+
+/// A note's content key wrapped to a one time key's `share_derivation_id`.
+///
+/// Kept apart from `OneTimeKey`, whose records can't grow.
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub struct WrappedKey(Vec<u8>);
+
+impl WrappedKey {
+    pub fn new(wrapped_key: Vec<u8>) -> Result<Self, String> {
+        if wrapped_key.is_empty() || wrapped_key.len() > WRAPPED_KEY_MAX_SIZE {
+            return Err(format!(
+                "Error::Wrapped key must be between 1 and {} bytes!",
+                WRAPPED_KEY_MAX_SIZE
+            ));
+        }
+
+        Ok(Self(wrapped_key))
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl Storable for WrappedKey {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        self.0.clone().into()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: WRAPPED_KEY_MAX_SIZE as u32,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct ShareLinkArgs {
+    pub public_key: Vec<u8>,
+    pub wrapped_key: Option<Vec<u8>>,
+    pub label: Option<String>,
+    pub max_reads: Option<u8>,
+    pub expires_in_secs: Option<u64>,
+}
+
+impl ShareLinkArgs {
+    /// The arguments an anonymous owner signs in the `create_share_link` proof.
+    pub fn proof_args(&self) -> Vec<Vec<u8>> {
+        vec![
+            self.public_key.clone(),
+            self.wrapped_key.clone().unwrap_or_default(),
+            self.label.clone().unwrap_or_default().into_bytes(),
+            vec![self.max_reads.unwrap_or(1)],
+            self.expires_in_secs.unwrap_or(0).to_le_bytes().to_vec(),
+        ]
+    }
+}
+
+/// A link to one note, opened with a signature from its own key pair.
+///
+/// Readers get the key for the link's `share_derivation_id`, the note's content
+/// key is wrapped to it so that key only opens what the link was made for.
+#[derive(Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct ShareLink {
+    text_id: Nonce,
+    owner: NoteOwner,
+    public_key: Vec<u8>,
+    wrapped_key: Option<Vec<u8>>,
+    label: Option<String>,
+    max_reads: u8,
+    reads: u8,
+    tries: u8,
+    created_at: NanoTimeStamp,
+    expires_at: NanoTimeStamp,
+}
+
+impl Storable for ShareLink {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1024,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for ShareLink {
+    const VERSION: u8 = 1;
+}
+
+impl ShareLink {
+    pub fn new(
+        text_id: Nonce,
+        owner: NoteOwner,
+        args: ShareLinkArgs,
+        now: NanoTimeStamp,
+    ) -> Result<Self, String> {
+        if args.public_key.len() != 48 {
+            return Err(format!(
+                "Error::Expected public key length 48, found {}!",
+                args.public_key.len()
+            ));
+        }
+
+        if let Some(wrapped_key) = &args.wrapped_key {
+            if wrapped_key.is_empty() || wrapped_key.len() > WRAPPED_KEY_MAX_SIZE {
+                return Err(format!(
+                    "Error::Wrapped key must be between 1 and {} bytes!",
+                    WRAPPED_KEY_MAX_SIZE
+                ));
+            }
+        }
+
+        if let Some(label) = &args.label {
+            if label.len() > SHARE_LINK_LABEL_MAX_SIZE {
+                return Err(format!(
+                    "Error::Label is larger than {} bytes!",
+                    SHARE_LINK_LABEL_MAX_SIZE
+                ));
+            }
+        }
+
+        let max_reads = args.max_reads.unwrap_or(1);
+
+        if max_reads == 0 {
+            return Err("Error::Max reads must be greater than zero!".to_string());
+        }
+
+        let expires_in = args
+            .expires_in_secs
+            .unwrap_or(get_config().one_time_key_expiration_secs);
+
+        if expires_in == 0 || expires_in > SHARE_LINK_MAX_EXPIRATION {
+            return Err(format!(
+                "Error::Expiration must be between 1 and {} seconds!",
+                SHARE_LINK_MAX_EXPIRATION
+            ));
+        }
+
+        Ok(Self {
+            text_id,
+            owner,
+            public_key: args.public_key,
+            wrapped_key: args.wrapped_key,
+            label: args.label,
+            max_reads,
+            reads: 0,
+            tries: 0,
+            created_at: now.clone(),
+            expires_at: now.add_secs(expires_in),
+        })
+    }
+
+    pub fn text_id(&self) -> &Nonce {
+        &self.text_id
+    }
+
+    pub fn owner(&self) -> &NoteOwner {
+        &self.owner
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn wrapped_key(&self) -> Option<Vec<u8>> {
+        self.wrapped_key.clone()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(&NanoTimeStamp::now())
+    }
+
+    pub fn is_expired_at(&self, now: &NanoTimeStamp) -> bool {
+        &self.expires_at < now
+    }
+
+    pub fn out_of_tries(&self) -> bool {
+        self.tries >= get_config().one_time_key_max_tries
+    }
+
+    pub fn add_try(&mut self) {
+        self.tries = self.tries.saturating_add(1);
+    }
+
+    /// Counts a successful read and returns whether the link is used up.
+    pub fn add_read(&mut self) -> bool {
+        self.reads = self.reads.saturating_add(1);
+
+        self.reads >= self.max_reads
+    }
+}
+
+/// What a reader gets from a share link or one time key.
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct SharedNote {
+    pub encrypted_text: Vec<u8>,
+    pub encrypted_key: Vec<u8>,
+    pub wrapped_key: Option<Vec<u8>>,
+}
+
+/// The vetKD keys a caller can request for their own identity.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum KeyScheme {
+    Ibe,
+    Symmetric,
+}
+
+impl KeyScheme {
+    pub fn derivation_path(&self) -> Vec<Vec<u8>> {
+        match self {
+            KeyScheme::Ibe => vec![b"ibe_encryption".to_vec()],
+            KeyScheme::Symmetric => vec![b"symmetric_key".to_vec()],
+        }
+    }
+}
+
+/// An encrypted vetKD key, cached per scheme, derivation id and transport key.
+#[derive(Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct CachedKey {
+    scheme: KeyScheme,
+    encrypted_key: Vec<u8>,
+    expires_at: NanoTimeStamp,
+}
+
+impl Storable for CachedKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for CachedKey {
+    const VERSION: u8 = 1;
+}
+
+impl CachedKey {
+    pub fn new(scheme: KeyScheme, encrypted_key: Vec<u8>, now: NanoTimeStamp) -> Self {
+        Self {
+            scheme,
+            encrypted_key,
+            expires_at: now.add_secs(CACHED_KEY_EXPIRATION),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(&NanoTimeStamp::now())
+    }
+
+    pub fn is_expired_at(&self, now: &NanoTimeStamp) -> bool {
+        &self.expires_at < now
+    }
+
+    /// The cached key, unless it expired or was cached for another scheme.
+    pub fn encrypted_key(&self, scheme: KeyScheme, now: &NanoTimeStamp) -> Option<Vec<u8>> {
+        if self.scheme != scheme || self.is_expired_at(now) {
+            return None;
+        }
+
+        Some(self.encrypted_key.clone())
+    }
+}
+
+#[derive(Default, Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct AnonymousUserData {
+    texts: Vec<Nonce>,
+    created_at: NanoTimeStamp,
+}
+
+impl Storable for AnonymousUserData {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 500,
+        is_fixed_size: false,
+    };
+}
+
+// v2 dropped the decryption key, cached keys live in `CachedKey` now
+impl Versioned for AnonymousUserData {
+    const VERSION: u8 = 2;
+}
+
+impl AnonymousUserData {
+    pub fn new() -> Self {
+        Self {
+            texts: vec![],
+            created_at: NanoTimeStamp::now(),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.created_at.elapsed().to_secs() > get_config().anonymous_user_expiration_secs
+    }
+
+    pub fn has_text_id(&self, text_id: &Nonce) -> bool {
+        self.texts.contains(text_id)
+    }
+
+    pub fn add_text_id(&mut self, text_id: Nonce) -> Result<(), String> {
+        let limit = get_config().anonymous_user_note_limit;
+
+        if self.texts.len() as u64 >= limit {
+            return Err(format!("Maximum of {} text are allowed", limit));
+        }
+
+        self.texts.push(text_id);
+
+        Ok(())
+    }
+
+    pub fn remove_text_id(&mut self, text_id: &Nonce) -> Result<(), &'static str> {
+        if self.texts.len() < 1 {
+            return Err("No text to remove");
+        }
+
+        self.texts.retain(|id| id != text_id);
+
+        Ok(())
+    }
+
+    pub fn iter_texts(&self) -> impl Iterator<Item = &Nonce> {
+        self.texts.iter()
+    }
+
+    pub fn get_created_at(&self) -> NanoTimeStamp {
+        self.created_at.clone()
+    }
+}
+
+#[derive(Serialize, Clone, CandidType, Deserialize)]
+pub struct AuthenticatedSignature {
+    pub signature: Vec<u8>,
+    pub created_at: NanoTimeStamp,
+}
+
+#[derive(Default, Serialize, Clone, CandidType, Deserialize)]
+pub struct UserData {
+    texts: Vec<Nonce>,
+    public_key: Vec<u8>,
+    signature: Option<AuthenticatedSignature>,
+}
+
+impl Storable for UserData {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 500,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for UserData {
+    const VERSION: u8 = 1;
+}
+
+impl UserData {
+    pub fn add_text_id(&mut self, text_id: Nonce) -> Result<(), String> {
+        let limit = get_config().user_note_limit;
+
+        if self.texts.len() as u64 >= limit {
+            return Err(format!("Maximum of {} text are allowed", limit));
+        }
+
+        self.texts.push(text_id);
+
+        Ok(())
+    }
+
+    pub fn has_text_id(&self, text_id: &Nonce) -> bool {
+        self.texts.contains(text_id)
+    }
+
+    /// Adds all of `text_ids` or none of them when they don't fit the limit.
+    pub fn add_text_ids(&mut self, text_ids: &[Nonce]) -> Result<(), String> {
+        let limit = get_config().user_note_limit;
+
+        if (self.texts.len() + text_ids.len()) as u64 > limit {
+            return Err(format!("Maximum of {} text are allowed", limit));
+        }
+
+        self.texts.extend_from_slice(text_ids);
+
+        Ok(())
+    }
+
+    pub fn remove_text_id(&mut self, text_id: &Nonce) -> Result<(), &'static str> {
+        if self.texts.len() < 1 {
+            return Err("No text to remove");
+        }
+
+        self.texts.retain(|id| id != text_id);
+
+        Ok(())
+    }
+
+    pub fn iter_texts(&self) -> impl Iterator<Item = &Nonce> {
+        self.texts.iter()
+    }
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub enum NotificationMessage {
+    Email {
+        email: String,
+        subject: String,
+        body: String,
+    },
+    Text {
+        phone_number: String,
+        body: String,
+    },
+}
+
+impl NotificationMessage {
+    pub fn validate(&self) -> Result<(), String> {
+        let (recipient, body) = match self {
+            NotificationMessage::Email {
+                email,
+                subject,
+                body,
+            } => {
+                if subject.len() > 200 {
+                    return Err("Error::Subject is too long!".to_string());
+                }
+
+                (email, body)
+            }
+            NotificationMessage::Text { phone_number, body } => (phone_number, body),
+        };
+
+        if recipient.is_empty() || recipient.len() > 254 {
+            return Err("Error::Invalid notification recipient!".to_string());
+        }
+
+        if body.len() > NOTIFICATION_BODY_MAX_SIZE {
+            return Err(format!(
+                "Error::Notification body is larger than {} bytes!",
+                NOTIFICATION_BODY_MAX_SIZE
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+/// Result of one delivery attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryOutcome {
+    Delivered,
+    Retry(String),
+    Failed(String),
+}
+
+#[derive(Debug, Serialize, Clone, CandidType, Deserialize)]
+pub struct Notification {
+    message: NotificationMessage,
+    status: DeliveryStatus,
+    attempts: u8,
+    last_error: Option<String>,
+    created_at: NanoTimeStamp,
+    updated_at: NanoTimeStamp,
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 4096,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for Notification {
+    const VERSION: u8 = 1;
+}
+
+impl Notification {
+    pub fn new(message: NotificationMessage, now: NanoTimeStamp) -> Result<Self, String> {
+        message.validate()?;
+
+        Ok(Self {
+            message,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            created_at: now.clone(),
+            updated_at: now,
+        })
+    }
+
+    pub fn message(&self) -> &NotificationMessage {
+        &self.message
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == DeliveryStatus::Pending
+    }
+
+    #[cfg(test)]
+    pub fn status(&self) -> DeliveryStatus {
+        self.status
+    }
+
+    #[cfg(test)]
+    pub fn attempts(&self) -> u8 {
+        self.attempts
+    }
+
+    #[cfg(test)]
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// Records an attempt and returns in how many seconds to retry, if at all.
+    pub fn record_attempt(
+        &mut self,
+        outcome: DeliveryOutcome,
+        provider: &NotificationProvider,
+        now: NanoTimeStamp,
+    ) -> Option<u64> {
+        self.attempts = self.attempts.saturating_add(1);
+        self.updated_at = now;
+
+        match outcome {
+            DeliveryOutcome::Delivered => {
+                self.status = DeliveryStatus::Delivered;
+                self.last_error = None;
+
+                None
+            }
+            DeliveryOutcome::Failed(error) => {
+                self.status = DeliveryStatus::Failed;
+                self.set_last_error(error);
+
+                None
+            }
+            DeliveryOutcome::Retry(error) => {
+                self.set_last_error(error);
+
+                if self.attempts >= provider.max_attempts {
+                    self.status = DeliveryStatus::Failed;
+
+                    None
+                } else {
+                    Some(provider.backoff_secs(self.attempts))
+                }
+            }
+        }
+    }
+
+    fn set_last_error(&mut self, error: String) {
+        self.last_error = Some(truncate_error(error));
+    }
+}
+
+/// The HTTPS endpoint notifications are posted to as JSON.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct NotificationProvider {
+    pub endpoint: String,
+    pub auth_token: Option<String>,
+    pub max_attempts: u8,
+    pub backoff_secs: u64,
+}
+
+impl Default for NotificationProvider {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            auth_token: None,
+            max_attempts: 5,
+            backoff_secs: 30,
+        }
+    }
+}
+
+impl NotificationProvider {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.endpoint.starts_with("https://") {
+            return Err("Error::Notification endpoint must use https!".to_string());
+        }
+
+        if self.max_attempts == 0 || self.backoff_secs == 0 {
+            return Err("Error::Attempts and backoff must be greater than zero!".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn is_configured(&self) -> bool {
+        !self.endpoint.is_empty()
+    }
+
+    /// Masks the auth token so the provider can be shown to admins.
+    pub fn redacted(self) -> Self {
+        Self {
+            auth_token: self.auth_token.map(|_| "********".to_string()),
+            ..self
+        }
+    }
+
+    pub fn backoff_secs(&self, attempts: u8) -> u64 {
+        exponential_backoff(self.backoff_secs, attempts)
+    }
+}
+
+impl Storable for NotificationProvider {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1024,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for NotificationProvider {
+    const VERSION: u8 = 1;
+}
+
+#[derive(CandidType, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+pub enum Task {
+    Initialize,
+    CleanUpAnonymousUsers,
+    CleanUpKeys,
+    SendEmail {
+        email: String,
+        subject: String,
+        body: String,
+    },
+    SendText {
+        phone_number: String,
+        body: String,
+    },
+    Notify(Nonce),
+}
+
+/// `Task` as it was stored directly in the timer heap, before payloads moved out of line.
+#[derive(CandidType, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+pub struct LegacyTask(pub Task);
+
+impl Storable for LegacyTask {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(&self.0))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        LegacyTask(decode(&bytes).unwrap())
     }
 
     const BOUND: Bound = Bound::Bounded {
@@ -324,3 +1737,540 @@ impl Storable for Task {
         is_fixed_size: true,
     };
 }
+
+impl Versioned for Task {
+    const VERSION: u8 = 1;
+}
+
+impl Task {
+    /// Tasks that reschedule themselves, only one of each is kept in the queue.
+    pub fn is_recurring(&self) -> bool {
+        matches!(
+            self,
+            Task::Initialize | Task::CleanUpKeys | Task::CleanUpAnonymousUsers
+        )
+    }
+
+    /// Seconds to wait before retrying after `attempts` failures, `None` once it should give up.
+    pub fn retry_in(attempts: u8) -> Option<u64> {
+        if attempts >= TASK_MAX_ATTEMPTS {
+            return None;
+        }
+
+        Some(exponential_backoff(TASK_RETRY_BACKOFF, attempts))
+    }
+}
+
+/// The payload of a queued task, the timer heap only holds its id.
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledTask {
+    task: Task,
+    attempts: u8,
+    due_at: NanoTimeStamp,
+}
+
+impl Storable for ScheduledTask {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 4096,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for ScheduledTask {
+    const VERSION: u8 = 1;
+}
+
+impl ScheduledTask {
+    pub fn new(task: Task, due_at: NanoTimeStamp) -> Self {
+        Self {
+            task,
+            attempts: 0,
+            due_at,
+        }
+    }
+
+    pub fn task(&self) -> &Task {
+        &self.task
+    }
+
+    /// Heap entries that don't match the due time were cancelled or moved.
+    pub fn is_due_at(&self, time: &NanoTimeStamp) -> bool {
+        &self.due_at == time
+    }
+
+    pub fn set_due_at(&mut self, due_at: NanoTimeStamp) {
+        self.due_at = due_at;
+    }
+
+    pub fn increment_attempts(&mut self) -> u8 {
+        self.attempts = self.attempts.saturating_add(1);
+
+        self.attempts
+    }
+}
+
+/// A task that kept failing until it ran out of attempts.
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
+pub struct DeadTask {
+    task: Task,
+    attempts: u8,
+    error: String,
+    failed_at: NanoTimeStamp,
+}
+
+impl Storable for DeadTask {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 4096 + 512,
+        is_fixed_size: false,
+    };
+}
+
+impl Versioned for DeadTask {
+    const VERSION: u8 = 1;
+}
+
+impl DeadTask {
+    pub fn new(task: Task, attempts: u8, error: String, failed_at: NanoTimeStamp) -> Self {
+        Self {
+            task,
+            attempts,
+            error: truncate_error(error),
+            failed_at,
+        }
+    }
+
+    pub fn into_task(self) -> Task {
+        self.task
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::with_simple_notes;
+
+    fn full_simple_notes() -> SimpleNotes {
+        let mut notes = SimpleNotes::default();
+
+        for _ in 0..SIMPLE_NOTES_PER_KEY {
+            notes.add_note("n".repeat(SIMPLE_NOTE_MAX_SIZE)).unwrap();
+        }
+
+        notes
+    }
+
+    #[test]
+    fn simple_notes_are_limited_per_key() {
+        with_simple_notes(|notes| notes.insert([4; 48], full_simple_notes()));
+
+        let mut full = with_simple_notes(|notes| notes.get(&[4; 48])).unwrap();
+
+        assert!(full.add_note("one more".to_string()).is_err());
+        assert_eq!(full.into_inner().len(), SIMPLE_NOTES_PER_KEY);
+
+        // another key starts from scratch
+        let mut other = with_simple_notes(|notes| notes.get(&[5; 48])).unwrap_or_default();
+
+        assert_eq!(other.add_note("first".to_string()), Ok(1));
+    }
+
+    #[test]
+    fn simple_notes_are_limited_in_size() {
+        let mut notes = SimpleNotes::default();
+
+        assert!(notes
+            .add_note("n".repeat(SIMPLE_NOTE_MAX_SIZE + 1))
+            .is_err());
+        assert_eq!(notes.add_note("n".repeat(SIMPLE_NOTE_MAX_SIZE)), Ok(1));
+    }
+
+    fn principal(byte: u8) -> RecipientIdentity {
+        RecipientIdentity::Principal(Principal::from_slice(&[byte; 29]))
+    }
+
+    #[test]
+    fn recipients_are_added_replaced_and_removed() {
+        let mut recipients = NoteRecipients::default();
+        let public_key = RecipientIdentity::PublicKey(vec![1; 48]);
+
+        recipients
+            .add(principal(1), vec![1], NanoTimeStamp::default())
+            .unwrap();
+        recipients
+            .add(public_key.clone(), vec![2], NanoTimeStamp::default())
+            .unwrap();
+        recipients
+            .add(principal(1), vec![3], NanoTimeStamp::default())
+            .unwrap();
+
+        assert_eq!(
+            recipients.identities(),
+            vec![public_key.clone(), principal(1)]
+        );
+        assert_eq!(recipients.get(&principal(1)).unwrap().wrapped_key(), &[3]);
+
+        recipients.remove(&principal(1)).unwrap();
+
+        assert!(recipients.get(&principal(1)).is_none());
+        assert!(recipients.remove(&principal(1)).is_err());
+
+        recipients.remove(&public_key).unwrap();
+
+        assert!(recipients.is_empty());
+    }
+
+    #[test]
+    fn invalid_recipients_are_rejected() {
+        let mut recipients = NoteRecipients::default();
+
+        for (identity, wrapped_key) in [
+            (
+                RecipientIdentity::Principal(Principal::anonymous()),
+                vec![1],
+            ),
+            (RecipientIdentity::PublicKey(vec![1; 47]), vec![1]),
+            (principal(1), vec![]),
+            (principal(1), vec![1; WRAPPED_KEY_MAX_SIZE + 1]),
+        ] {
+            assert!(recipients
+                .add(identity, wrapped_key, NanoTimeStamp::default())
+                .is_err());
+        }
+
+        assert!(recipients.is_empty());
+    }
+
+    #[test]
+    fn recipients_are_limited_per_note() {
+        let mut recipients = NoteRecipients::default();
+
+        for byte in 0..NOTE_RECIPIENTS_MAX as u8 {
+            recipients
+                .add(
+                    principal(byte),
+                    vec![1; WRAPPED_KEY_MAX_SIZE],
+                    NanoTimeStamp(u64::MAX),
+                )
+                .unwrap();
+        }
+
+        assert!(recipients
+            .add(principal(u8::MAX), vec![1], NanoTimeStamp::default())
+            .is_err());
+
+        // an existing recipient can still get a new key
+        recipients
+            .add(principal(0), vec![2], NanoTimeStamp::default())
+            .unwrap();
+
+        assert_eq!(recipients.identities().len(), NOTE_RECIPIENTS_MAX);
+    }
+
+    #[test]
+    fn full_recipients_fit_their_bound() {
+        let mut recipients = NoteRecipients::default();
+
+        for byte in 0..NOTE_RECIPIENTS_MAX as u8 {
+            recipients
+                .add(
+                    principal(byte),
+                    vec![1; WRAPPED_KEY_MAX_SIZE],
+                    NanoTimeStamp(u64::MAX),
+                )
+                .unwrap();
+        }
+
+        assert!(recipients.to_bytes().len() <= NoteRecipients::BOUND.max_size() as usize);
+    }
+
+    #[test]
+    fn full_simple_notes_fit_their_bound() {
+        let notes = full_simple_notes();
+
+        assert!(notes.to_bytes().len() <= SimpleNotes::BOUND.max_size() as usize);
+    }
+
+    fn full_note_ids() -> Vec<Nonce> {
+        (0..NOTE_LIMIT_MAX).map(|_| Nonce::from(u64::MAX)).collect()
+    }
+
+    #[test]
+    fn note_limits_are_bounded() {
+        let config = Config {
+            user_note_limit: NOTE_LIMIT_MAX,
+            anonymous_user_note_limit: NOTE_LIMIT_MAX,
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = Config {
+            user_note_limit: NOTE_LIMIT_MAX + 1,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            anonymous_user_note_limit: NOTE_LIMIT_MAX + 1,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn users_at_the_note_limit_fit_their_bound() {
+        let user = UserData {
+            texts: full_note_ids(),
+            public_key: vec![u8::MAX; 48],
+            signature: None,
+        };
+        assert!(user.to_bytes().len() <= UserData::BOUND.max_size() as usize);
+
+        let anonymous_user = AnonymousUserData {
+            texts: full_note_ids(),
+            created_at: NanoTimeStamp(u64::MAX),
+        };
+        assert!(anonymous_user.to_bytes().len() <= AnonymousUserData::BOUND.max_size() as usize);
+    }
+
+    #[test]
+    fn task_retries_back_off_until_they_give_up() {
+        let mut scheduled = ScheduledTask::new(Task::CleanUpKeys, NanoTimeStamp::default());
+        let mut delays = vec![];
+
+        while let Some(retry_in) = Task::retry_in(scheduled.increment_attempts()) {
+            delays.push(retry_in);
+        }
+
+        assert_eq!(delays, [10, 20, 40, 80, 160, 320, 640]);
+        assert_eq!(scheduled.attempts, TASK_MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(
+            exponential_backoff(TASK_RETRY_BACKOFF, 0),
+            TASK_RETRY_BACKOFF
+        );
+        assert_eq!(exponential_backoff(TASK_RETRY_BACKOFF, 9), 2560);
+        assert_eq!(exponential_backoff(TASK_RETRY_BACKOFF, 10), MAX_BACKOFF);
+        assert_eq!(exponential_backoff(u64::MAX, u8::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn dead_tasks_keep_a_bounded_error() {
+        let task = Task::SendText {
+            phone_number: "+15550100".to_string(),
+            body: "b".repeat(NOTIFICATION_BODY_MAX_SIZE),
+        };
+
+        let dead_task = DeadTask::new(
+            task.clone(),
+            TASK_MAX_ATTEMPTS,
+            "é".repeat(ERROR_MAX_SIZE),
+            NanoTimeStamp(u64::MAX),
+        );
+
+        assert!(dead_task.error.len() <= ERROR_MAX_SIZE);
+        assert!(dead_task.to_bytes().len() <= DeadTask::BOUND.max_size() as usize);
+        assert_eq!(dead_task.into_task(), task);
+    }
+
+    #[test]
+    fn largest_scheduled_task_fits_its_bound() {
+        let task = Task::SendEmail {
+            email: "e".repeat(254),
+            subject: "s".repeat(200),
+            body: "b".repeat(NOTIFICATION_BODY_MAX_SIZE),
+        };
+
+        let message = NotificationMessage::Email {
+            email: "e".repeat(254),
+            subject: "s".repeat(200),
+            body: "b".repeat(NOTIFICATION_BODY_MAX_SIZE),
+        };
+        assert!(message.validate().is_ok());
+
+        let scheduled = ScheduledTask::new(task, NanoTimeStamp(u64::MAX));
+
+        assert!(scheduled.to_bytes().len() <= ScheduledTask::BOUND.max_size() as usize);
+    }
+
+    fn share_link_args() -> ShareLinkArgs {
+        ShareLinkArgs {
+            public_key: vec![2; 48],
+            wrapped_key: None,
+            label: None,
+            max_reads: None,
+            expires_in_secs: None,
+        }
+    }
+
+    fn share_link(args: ShareLinkArgs) -> Result<ShareLink, String> {
+        ShareLink::new(
+            Nonce::from(1),
+            NoteOwner::Anonymous(vec![1; 48]),
+            args,
+            NanoTimeStamp(0),
+        )
+    }
+
+    #[test]
+    fn share_links_validate_their_args() {
+        let invalid = [
+            ShareLinkArgs {
+                public_key: vec![2; 47],
+                ..share_link_args()
+            },
+            ShareLinkArgs {
+                wrapped_key: Some(vec![]),
+                ..share_link_args()
+            },
+            ShareLinkArgs {
+                wrapped_key: Some(vec![1; WRAPPED_KEY_MAX_SIZE + 1]),
+                ..share_link_args()
+            },
+            ShareLinkArgs {
+                label: Some("l".repeat(SHARE_LINK_LABEL_MAX_SIZE + 1)),
+                ..share_link_args()
+            },
+            ShareLinkArgs {
+                max_reads: Some(0),
+                ..share_link_args()
+            },
+            ShareLinkArgs {
+                expires_in_secs: Some(0),
+                ..share_link_args()
+            },
+            ShareLinkArgs {
+                expires_in_secs: Some(SHARE_LINK_MAX_EXPIRATION + 1),
+                ..share_link_args()
+            },
+        ];
+
+        for args in invalid {
+            assert!(share_link(args).is_err());
+        }
+
+        let link = share_link(ShareLinkArgs {
+            label: Some("l".repeat(SHARE_LINK_LABEL_MAX_SIZE)),
+            ..share_link_args()
+        })
+        .unwrap();
+
+        assert_eq!(link.label, Some("l".repeat(SHARE_LINK_LABEL_MAX_SIZE)));
+        assert_eq!(link.max_reads, 1);
+    }
+
+    #[test]
+    fn share_links_expire_after_their_expiration() {
+        let link = share_link(ShareLinkArgs {
+            expires_in_secs: Some(60),
+            ..share_link_args()
+        })
+        .unwrap();
+
+        assert!(!link.is_expired_at(&NanoTimeStamp(60 * 1_000_000_000)));
+        assert!(link.is_expired_at(&NanoTimeStamp(60 * 1_000_000_000 + 1)));
+
+        // without an expiration links last as long as one time keys
+        let link = share_link(share_link_args()).unwrap();
+        let expiration = get_config().one_time_key_expiration_secs;
+
+        assert!(!link.is_expired_at(&NanoTimeStamp(expiration * 1_000_000_000)));
+        assert!(link.is_expired_at(&NanoTimeStamp(expiration * 1_000_000_000 + 1)));
+    }
+
+    #[test]
+    fn share_links_are_used_up_after_max_reads() {
+        let mut link = share_link(ShareLinkArgs {
+            max_reads: Some(2),
+            ..share_link_args()
+        })
+        .unwrap();
+
+        assert!(!link.add_read());
+        assert!(link.add_read());
+
+        for _ in 0..get_config().one_time_key_max_tries {
+            assert!(!link.out_of_tries());
+            link.add_try();
+        }
+        assert!(link.out_of_tries());
+    }
+
+    #[test]
+    fn full_share_links_fit_their_bound() {
+        let link = ShareLink::new(
+            Nonce::from(u64::MAX),
+            NoteOwner::Anonymous(vec![u8::MAX; 48]),
+            ShareLinkArgs {
+                public_key: vec![u8::MAX; 48],
+                wrapped_key: Some(vec![u8::MAX; WRAPPED_KEY_MAX_SIZE]),
+                label: Some("l".repeat(SHARE_LINK_LABEL_MAX_SIZE)),
+                max_reads: Some(u8::MAX),
+                expires_in_secs: Some(SHARE_LINK_MAX_EXPIRATION),
+            },
+            NanoTimeStamp(u64::MAX / 2),
+        )
+        .unwrap();
+
+        assert!(link.to_bytes().len() <= ShareLink::BOUND.max_size() as usize);
+    }
+
+    #[test]
+    fn one_time_keys_belong_to_who_shared_them() {
+        let owner_key = [1; 48];
+        let anonymous = OneTimeKey::new(owner_key, NanoTimeStamp(0));
+
+        assert_eq!(anonymous.owner(), NoteOwner::Anonymous(owner_key.to_vec()));
+        assert_eq!(anonymous.public_key(), owner_key);
+
+        let principal = Principal::from_slice(&[7; 29]);
+        let link_key = [2; 48];
+        let authenticated = OneTimeKey::new_for_user(principal, link_key, NanoTimeStamp(0));
+
+        // readers prove the link key, the owner's own keys never come into it
+        assert_eq!(authenticated.owner(), NoteOwner::User(principal));
+        assert_eq!(authenticated.public_key(), link_key);
+        assert!(!authenticated.is_expired_at(&NanoTimeStamp(0)));
+    }
+
+    #[test]
+    fn one_time_keys_expire_with_the_config() {
+        let key = OneTimeKey::new([1; 48], NanoTimeStamp(0));
+        let expiration = get_config().one_time_key_expiration_secs * 1_000_000_000;
+
+        assert!(!key.is_expired_at(&NanoTimeStamp(expiration)));
+        assert!(key.is_expired_at(&NanoTimeStamp(expiration + 1)));
+    }
+
+    #[test]
+    fn full_one_time_keys_fit_their_bound() {
+        let key = OneTimeKey {
+            tries: u8::MAX,
+            ..OneTimeKey::new_for_user(
+                Principal::from_slice(&[u8::MAX; 29]),
+                [u8::MAX; 48],
+                NanoTimeStamp(u64::MAX / 2),
+            )
+        };
+
+        assert!(key.to_bytes().len() <= OneTimeKey::BOUND.max_size() as usize);
+    }
+}
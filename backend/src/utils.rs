@@ -1,8 +1,12 @@
+use b3_utils::{nonce::Nonce, vetkd::verify_pairing};
 use candid::Principal;
+use sha2::{Digest, Sha256};
 
 use crate::{
-    store::with_anonymous_user,
-    types::{AnonymousUserData, PublicKey},
+    store::{
+        get_challenge_counter, get_role_grant, increment_challenge_counter, with_anonymous_user,
+    },
+    types::{AnonymousProof, AnonymousUserData, KeyScheme, NoteOwner, PublicKey, Role},
 };
 
 pub fn vec_to_fixed_array<const N: usize>(slice: &[u8]) -> Result<[u8; N], String> {
@@ -33,6 +37,205 @@ pub fn caller_is_controller() -> Result<(), String> {
     Ok(())
 }
 
+pub fn caller_has_role(role: Role) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+
+    if ic_cdk::api::is_controller(&caller) {
+        return Ok(());
+    }
+
+    match get_role_grant(&caller) {
+        Some(grant) if grant.has_role(role) => Ok(()),
+        _ => Err(format!("Caller does not have the {:?} role", role)),
+    }
+}
+
+pub fn caller_is_admin() -> Result<(), String> {
+    caller_has_role(Role::Admin)
+}
+
+pub fn caller_is_auditor() -> Result<(), String> {
+    caller_has_role(Role::Auditor)
+}
+
+/// The vetKD derivation id for keys handed out to `caller`.
+///
+/// Anonymous callers all share `Principal::anonymous()`, so their keys are bound
+/// to their public key instead; the key is encrypted to that same transport key,
+/// so only its holder can use it. Principals are at most 29 bytes and public keys
+/// 48, so the two kinds of ids never collide.
+pub fn caller_derivation_id(caller: Principal, public_key: &PublicKey) -> Vec<u8> {
+    if caller == Principal::anonymous() {
+        public_key.to_vec()
+    } else {
+        caller.as_slice().to_vec()
+    }
+}
+
+const SHARE_DERIVATION_DOMAIN: &[u8] = b"b3note-share";
+
+/// The vetKD derivation id a note is shared under with a one time key or link key.
+///
+/// Readers only ever get the key for this id, never the one of the owner. It is
+/// 32 bytes long, so it can't collide with an id from `caller_derivation_id`.
+pub fn share_derivation_id(text_id: &Nonce, public_key: &[u8]) -> Vec<u8> {
+    args_hash(&[SHARE_DERIVATION_DOMAIN, &text_id.to_le_bytes(), public_key]).to_vec()
+}
+
 pub fn get_anonymous_user(public_key: &PublicKey) -> Result<AnonymousUserData, String> {
     with_anonymous_user(public_key, |anonymous_user| Ok(anonymous_user.clone()))
 }
+
+/// Resolves who owns a note written by `caller`; anonymous callers are identified by their public key.
+pub fn caller_note_owner(
+    caller: Principal,
+    public_key: Option<Vec<u8>>,
+) -> Result<NoteOwner, String> {
+    if caller != Principal::anonymous() {
+        return Ok(NoteOwner::User(caller));
+    }
+
+    let public_key = public_key.ok_or("Error::public key is required for anonymous user!")?;
+    let public_key: PublicKey = vec_to_fixed_array(&public_key)?;
+
+    Ok(NoteOwner::Anonymous(public_key.to_vec()))
+}
+
+/// Hashes call arguments for an `AnonymousProof`, each argument is length prefixed.
+pub fn args_hash(args: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    for arg in args {
+        hasher.update((arg.len() as u64).to_be_bytes());
+        hasher.update(arg);
+    }
+
+    hasher.finalize().into()
+}
+
+/// Verifies the proof for `method` called with `args` and consumes the key's challenge.
+///
+/// Nothing is stored for a key until it has passed a proof.
+pub fn verify_anonymous_proof(
+    proof: &AnonymousProof,
+    method: &str,
+    args: &[&[u8]],
+) -> Result<PublicKey, String> {
+    let public_key: PublicKey = vec_to_fixed_array(&proof.public_key)?;
+
+    let challenge = AnonymousProof::challenge(
+        &public_key,
+        get_challenge_counter(&public_key),
+        method,
+        &args_hash(args),
+    );
+
+    match verify_pairing(&public_key, &proof.signature, &challenge) {
+        Ok(true) => {
+            increment_challenge_counter(&public_key);
+
+            Ok(public_key)
+        }
+        _ => Err("Error::Invalid ownership proof!".to_string()),
+    }
+}
+
+/// Like `caller_note_owner`, but anonymous callers have to prove they control the public key.
+pub fn caller_proven_owner(
+    caller: Principal,
+    proof: Option<AnonymousProof>,
+    method: &str,
+    args: &[&[u8]],
+) -> Result<NoteOwner, String> {
+    if caller != Principal::anonymous() {
+        return Ok(NoteOwner::User(caller));
+    }
+
+    let proof = proof.ok_or("Error::Ownership proof is required for anonymous user!")?;
+    let public_key = verify_anonymous_proof(&proof, method, args)?;
+
+    Ok(NoteOwner::Anonymous(public_key.to_vec()))
+}
+
+/// Time-lock keys are derived for the unlock epoch, in seconds since the unix epoch.
+pub fn timelock_derivation_id(epoch: u64) -> Vec<u8> {
+    epoch.to_be_bytes().to_vec()
+}
+
+/// Checks that the time-lock of `epoch` has opened by `now_secs`.
+pub fn check_timelock(epoch: u64, now_secs: u64) -> Result<(), String> {
+    if now_secs < epoch {
+        return Err(format!(
+            "Error::Time-lock opens in {} seconds!",
+            epoch - now_secs
+        ));
+    }
+
+    Ok(())
+}
+
+/// Where a caller's encrypted key is cached, the transport key is part of the
+/// id because the cached key is only readable with its secret.
+pub fn key_cache_id(scheme: KeyScheme, derivation_id: &[u8], transport_key: &[u8]) -> [u8; 32] {
+    let derivation_path = scheme.derivation_path().concat();
+
+    args_hash(&[&derivation_path, derivation_id, transport_key])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_derivation_ids_are_scoped_to_note_and_key() {
+        let public_key = [1; 48];
+
+        let derivation_id = share_derivation_id(&Nonce::from(1), &public_key);
+
+        assert_eq!(
+            derivation_id,
+            share_derivation_id(&Nonce::from(1), &public_key)
+        );
+        assert_ne!(
+            derivation_id,
+            share_derivation_id(&Nonce::from(2), &public_key)
+        );
+        assert_ne!(
+            derivation_id,
+            share_derivation_id(&Nonce::from(1), &[2; 48])
+        );
+
+        // an anonymous owner sharing with their own key keeps their identity key
+        assert_ne!(
+            derivation_id,
+            caller_derivation_id(Principal::anonymous(), &public_key)
+        );
+        assert_eq!(derivation_id.len(), 32);
+    }
+
+    #[test]
+    fn timelock_opens_at_its_epoch() {
+        let epoch = 1_800_000_000;
+
+        assert_eq!(
+            check_timelock(epoch, epoch - 1),
+            Err("Error::Time-lock opens in 1 seconds!".to_string())
+        );
+        assert_eq!(check_timelock(epoch, epoch), Ok(()));
+        assert_eq!(check_timelock(epoch, epoch + 1), Ok(()));
+        assert_eq!(check_timelock(0, 0), Ok(()));
+    }
+
+    #[test]
+    fn key_cache_ids_change_with_scheme_derivation_id_and_transport_key() {
+        let cache_id = key_cache_id(KeyScheme::Ibe, &[1; 29], &[2; 48]);
+
+        assert_eq!(cache_id, key_cache_id(KeyScheme::Ibe, &[1; 29], &[2; 48]));
+        assert_ne!(
+            cache_id,
+            key_cache_id(KeyScheme::Symmetric, &[1; 29], &[2; 48])
+        );
+        assert_ne!(cache_id, key_cache_id(KeyScheme::Ibe, &[3; 29], &[2; 48]));
+        assert_ne!(cache_id, key_cache_id(KeyScheme::Ibe, &[1; 29], &[4; 48]));
+    }
+}
@@ -0,0 +1,314 @@
+//! Minimal-pubkey-size BLS signatures over BLS12-381
+//!
+//! Public keys are compressed G1 points (48 bytes) and signatures compressed
+//! G2 points (96 bytes), following the message augmentation and proof of
+//! possession ciphersuites of draft-irtf-cfrg-bls-signature-05, so signatures
+//! can be checked by any other implementation of those ciphersuites.
+
+use ic_bls12_381::{
+    hash_to_curve::{ExpandMsgXmd, HashToCurve},
+    G1Affine, G2Affine, G2Prepared, G2Projective, Scalar,
+};
+use pairing::group::{Curve, Group};
+use std::ops::Neg;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::{gt_multipairing, ro, TransportSecretKey, VetKdError, G1AFFINE_BYTES};
+
+const SECRET_KEY_BYTES: usize = 32;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+/// The ciphersuite a signature is created and verified under
+pub enum Ciphersuite {
+    /// `BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_AUG_`, the signer's public key
+    /// is prepended to every message
+    MessageAugmentation,
+    /// `BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_`, public keys must come
+    /// with a proof of possession before their signatures are aggregated
+    ProofOfPossession,
+}
+
+impl Ciphersuite {
+    /// The domain separation tag used to hash messages
+    pub fn dst(&self) -> &'static [u8] {
+        match self {
+            Self::MessageAugmentation => b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_AUG_",
+            Self::ProofOfPossession => b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_",
+        }
+    }
+
+    fn hash_to_g2(&self, public_key: &PublicKey, msg: &[u8]) -> G2Affine {
+        match self {
+            Self::MessageAugmentation => {
+                let mut input = Vec::with_capacity(G1AFFINE_BYTES + msg.len());
+                input.extend_from_slice(&public_key.serialize());
+                input.extend_from_slice(msg);
+                hash_to_g2(&input, self.dst())
+            }
+            Self::ProofOfPossession => hash_to_g2(msg, self.dst()),
+        }
+    }
+}
+
+/// The domain separation tag of proofs of possession
+pub const POP_DST: &[u8] = b"BLS_POP_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+/// A BLS secret key
+pub struct SecretKey {
+    secret_key: Scalar,
+}
+
+impl SecretKey {
+    /// Deserializes a secret key from its 32-byte big-endian encoding
+    ///
+    /// Returns Err if the encoding is not a non-zero scalar
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, VetKdError> {
+        let mut le_bytes: [u8; SECRET_KEY_BYTES] =
+            bytes.try_into().map_err(|_e| VetKdError::InvalidLength {
+                expected: SECRET_KEY_BYTES,
+                actual: bytes.len(),
+            })?;
+        le_bytes.reverse();
+
+        let secret_key = crate::option_from_ctoption(Scalar::from_bytes(&le_bytes));
+        le_bytes.zeroize();
+
+        match secret_key {
+            Some(secret_key) if secret_key != Scalar::zero() => Ok(Self { secret_key }),
+            _ => Err(VetKdError::InvalidSecretKey),
+        }
+    }
+
+    /// Serializes this secret key to its 32-byte big-endian encoding
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = self.secret_key.to_bytes();
+        bytes.reverse();
+        let output = bytes.to_vec();
+        bytes.zeroize();
+        output
+    }
+
+    /// Returns the public key associated with this secret key
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            point: (G1Affine::generator() * self.secret_key).to_affine(),
+        }
+    }
+
+    /// Signs a message under the given ciphersuite
+    pub fn sign(&self, ciphersuite: Ciphersuite, msg: &[u8]) -> Signature {
+        let hashed_msg = ciphersuite.hash_to_g2(&self.public_key(), msg);
+
+        Signature {
+            point: (hashed_msg * self.secret_key).to_affine(),
+        }
+    }
+
+    /// Proves possession of this secret key for its public key
+    pub fn prove_possession(&self) -> Signature {
+        let hashed_pk = hash_to_g2(&self.public_key().serialize(), POP_DST);
+
+        Signature {
+            point: (hashed_pk * self.secret_key).to_affine(),
+        }
+    }
+}
+
+impl From<&TransportSecretKey> for SecretKey {
+    fn from(tsk: &TransportSecretKey) -> Self {
+        Self {
+            secret_key: tsk.secret_key,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+/// A BLS public key
+pub struct PublicKey {
+    point: G1Affine,
+}
+
+impl PublicKey {
+    /// Deserializes a compressed public key
+    ///
+    /// Returns Err if the encoding is not a valid point of the prime order
+    /// subgroup, or is the identity
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, VetKdError> {
+        let point = crate::deserialize_g1(bytes)?;
+
+        if bool::from(point.is_identity()) {
+            return Err(VetKdError::InvalidPoint);
+        }
+
+        Ok(Self { point })
+    }
+
+    /// Serializes this public key in compressed form
+    pub fn serialize(&self) -> Vec<u8> {
+        self.point.to_compressed().to_vec()
+    }
+
+    /// Verifies a signature on a message under the given ciphersuite
+    pub fn verify(&self, ciphersuite: Ciphersuite, msg: &[u8], signature: &Signature) -> bool {
+        let hashed_msg = ciphersuite.hash_to_g2(self, msg);
+
+        pairing_product_is_identity(&signature.point, &[(self.point, hashed_msg)])
+    }
+
+    /// Verifies a proof of possession for this public key
+    pub fn verify_possession(&self, proof: &Signature) -> bool {
+        let hashed_pk = hash_to_g2(&self.serialize(), POP_DST);
+
+        pairing_product_is_identity(&proof.point, &[(self.point, hashed_pk)])
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+/// A BLS signature, or an aggregate of several
+pub struct Signature {
+    point: G2Affine,
+}
+
+impl Signature {
+    /// Deserializes a compressed signature
+    ///
+    /// Returns Err if the encoding is not a valid point of the prime order
+    /// subgroup
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, VetKdError> {
+        Ok(Self {
+            point: crate::deserialize_g2(bytes)?,
+        })
+    }
+
+    /// Serializes this signature in compressed form
+    pub fn serialize(&self) -> Vec<u8> {
+        self.point.to_compressed().to_vec()
+    }
+
+    /// Aggregates signatures into a single one
+    ///
+    /// Returns Err if no signatures are given
+    pub fn aggregate(signatures: &[Signature]) -> Result<Self, VetKdError> {
+        if signatures.is_empty() {
+            return Err(VetKdError::InvalidLength {
+                expected: 1,
+                actual: 0,
+            });
+        }
+
+        let point = signatures
+            .iter()
+            .fold(G2Projective::identity(), |sum, signature| {
+                sum + signature.point
+            });
+
+        Ok(Self {
+            point: point.to_affine(),
+        })
+    }
+}
+
+/// Verifies an aggregate signature on messages by their respective signers
+///
+/// Under [`Ciphersuite::ProofOfPossession`] every public key must have had its
+/// proof of possession verified beforehand. Returns false if no messages are
+/// given or the number of public keys and messages differ.
+pub fn aggregate_verify(
+    ciphersuite: Ciphersuite,
+    public_keys: &[PublicKey],
+    msgs: &[&[u8]],
+    signature: &Signature,
+) -> bool {
+    if public_keys.is_empty() || public_keys.len() != msgs.len() {
+        return false;
+    }
+
+    let terms = public_keys
+        .iter()
+        .zip(msgs)
+        .map(|(public_key, msg)| (public_key.point, ciphersuite.hash_to_g2(public_key, msg)))
+        .collect::<Vec<_>>();
+
+    pairing_product_is_identity(&signature.point, &terms)
+}
+
+/// Verifies an aggregate signature by several signers on the same message
+///
+/// Only sound under [`Ciphersuite::ProofOfPossession`] and with every public
+/// key's proof of possession verified beforehand.
+pub fn fast_aggregate_verify(public_keys: &[PublicKey], msg: &[u8], signature: &Signature) -> bool {
+    if public_keys.is_empty() {
+        return false;
+    }
+
+    let aggregate_key = public_keys
+        .iter()
+        .fold(ic_bls12_381::G1Projective::identity(), |sum, public_key| {
+            sum + public_key.point
+        })
+        .to_affine();
+
+    let hashed_msg = hash_to_g2(msg, Ciphersuite::ProofOfPossession.dst());
+
+    pairing_product_is_identity(&signature.point, &[(aggregate_key, hashed_msg)])
+}
+
+/// Verifies independent signatures at once, with a single final exponentiation
+///
+/// Returns true only if every signature is valid. Each signature is weighted by
+/// a 128-bit scalar derived from the whole batch, so invalid signatures can not
+/// be crafted to cancel each other out. An empty batch is valid.
+pub fn verify_batch(ciphersuite: Ciphersuite, items: &[(PublicKey, &[u8], Signature)]) -> bool {
+    let mut ro = ro::RandomOracle::new("ic-crypto-vetkd-bls12-381-bls-verify-batch");
+    ro.update_bin(ciphersuite.dst());
+    for (public_key, msg, signature) in items {
+        ro.update_bin(&public_key.serialize());
+        ro.update_bin(msg);
+        ro.update_bin(&signature.serialize());
+    }
+
+    let mut signature_sum = G2Projective::identity();
+    let mut terms = Vec::with_capacity(items.len());
+
+    for (i, (public_key, msg, signature)) in items.iter().enumerate() {
+        let mut ro = ro.clone();
+        ro.update_bin(&(i as u64).to_be_bytes());
+        let r = ro.finalize_to_array::<16>();
+
+        let r = Scalar::from_raw([
+            u64::from_be_bytes(r[8..].try_into().expect("8 bytes")),
+            u64::from_be_bytes(r[..8].try_into().expect("8 bytes")),
+            0,
+            0,
+        ]);
+
+        signature_sum += signature.point * r;
+        terms.push((
+            (public_key.point * r).to_affine(),
+            ciphersuite.hash_to_g2(public_key, msg),
+        ));
+    }
+
+    pairing_product_is_identity(&signature_sum.to_affine(), &terms)
+}
+
+/// Checks e(g1, signature) == prod e(pk_i, h_i) with one multi Miller loop
+pub(crate) fn pairing_product_is_identity(
+    signature: &G2Affine,
+    terms: &[(G1Affine, G2Affine)],
+) -> bool {
+    let neg_g1 = G1Affine::generator().neg();
+
+    let prepared = std::iter::once((neg_g1, G2Prepared::from(*signature)))
+        .chain(terms.iter().map(|(g1, g2)| (*g1, G2Prepared::from(*g2))))
+        .collect::<Vec<_>>();
+
+    let terms = prepared.iter().map(|(g1, g2)| (g1, g2)).collect::<Vec<_>>();
+
+    bool::from(gt_multipairing(&terms).is_identity())
+}
+
+fn hash_to_g2(msg: &[u8], dst: &[u8]) -> G2Affine {
+    <G2Projective as HashToCurve<ExpandMsgXmd<sha2::Sha256>>>::hash_to_curve(msg, dst).to_affine()
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+
+#[cfg(feature = "js")]
+use wasm_bindgen::prelude::*;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+/// Error returned by the functions of this crate
+pub enum VetKdError {
+    /// An input did not have the expected length in bytes
+    InvalidLength {
+        /// The expected length, or the bound it violated for variable sized inputs
+        expected: usize,
+        /// The length of the input
+        actual: usize,
+    },
+    /// An input was not the encoding of a valid elliptic curve point
+    InvalidPoint,
+    /// A provided seed was not 32 bytes long
+    InvalidSeed,
+    /// An input was not the encoding of a valid secret key
+    InvalidSecretKey,
+    /// An encrypted key did not match the derived public key and derivation id
+    VerificationFailed,
+    /// Encrypting a message failed
+    EncryptionFailed,
+    /// A ciphertext was malformed or not created for this key
+    DecryptionFailed,
+    /// A ciphertext or stream was created by an unsupported version
+    UnsupportedVersion(u8),
+    /// A stream chunk size was zero
+    InvalidChunkSize,
+    /// A stream was used after its last chunk
+    StreamFinished,
+}
+
+impl VetKdError {
+    /// Returns a stable name for the kind of error
+    ///
+    /// Under the `js` feature this becomes the prefix of the error message
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidLength { .. } => "InvalidLength",
+            Self::InvalidPoint => "InvalidPoint",
+            Self::InvalidSeed => "InvalidSeed",
+            Self::InvalidSecretKey => "InvalidSecretKey",
+            Self::VerificationFailed => "VerificationFailed",
+            Self::EncryptionFailed => "EncryptionFailed",
+            Self::DecryptionFailed => "DecryptionFailed",
+            Self::UnsupportedVersion(_) => "UnsupportedVersion",
+            Self::InvalidChunkSize => "InvalidChunkSize",
+            Self::StreamFinished => "StreamFinished",
+        }
+    }
+}
+
+impl fmt::Display for VetKdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength { expected, actual } => {
+                write!(
+                    f,
+                    "invalid length: expected {} bytes but got {}",
+                    expected, actual
+                )
+            }
+            Self::InvalidPoint => write!(f, "invalid elliptic curve point"),
+            Self::InvalidSeed => write!(f, "seed must be 32 bytes long"),
+            Self::InvalidSecretKey => write!(f, "invalid secret key"),
+            Self::VerificationFailed => write!(f, "invalid encrypted key: verification failed"),
+            Self::EncryptionFailed => write!(f, "encryption failed"),
+            Self::DecryptionFailed => write!(f, "decryption failed"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            Self::InvalidChunkSize => write!(f, "chunk size must not be zero"),
+            Self::StreamFinished => write!(f, "stream already finished"),
+        }
+    }
+}
+
+impl std::error::Error for VetKdError {}
+
+#[cfg(feature = "js")]
+impl From<VetKdError> for JsValue {
+    /// Thrown in Javascript as an `Error` whose message starts with the code
+    fn from(error: VetKdError) -> Self {
+        JsError::new(&format!("{}: {}", error.code(), error)).into()
+    }
+}
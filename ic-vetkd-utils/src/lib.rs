@@ -9,16 +9,23 @@
 
 use ic_bls12_381::{
     hash_to_curve::{ExpandMsgXmd, HashToCurve},
-    pairing, G1Affine, G1Projective, G2Affine, G2Prepared, G2Projective, Gt, Scalar,
+    G1Affine, G1Projective, G2Affine, G2Prepared, G2Projective, Gt, Scalar,
 };
 use pairing::group::Curve;
 use rand::SeedableRng;
 use rand_chacha::ChaCha20Rng;
+use std::ops::Mul;
 use std::ops::Neg;
-use std::{array::TryFromSliceError, ops::Mul};
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
+pub mod bls;
+mod error;
 mod ro;
+pub mod stream;
+mod symmetric;
+
+pub use error::VetKdError;
+pub use symmetric::{SymmetricNoteCipher, SYMMETRIC_NOTE_CIPHER_DOMAIN};
 
 #[cfg(feature = "js")]
 use wasm_bindgen::prelude::*;
@@ -41,8 +48,8 @@ pub struct TransportSecretKey {
 impl TransportSecretKey {
     #[cfg_attr(feature = "js", wasm_bindgen(constructor))]
     /// Creates a transport secret key from a 32-byte seed.
-    pub fn from_seed(seed: Vec<u8>) -> Result<TransportSecretKey, String> {
-        let seed_32_bytes: [u8; 32] = seed.try_into().map_err(|_e| "seed not 32 bytes")?;
+    pub fn from_seed(seed: Vec<u8>) -> Result<TransportSecretKey, VetKdError> {
+        let seed_32_bytes: [u8; 32] = seed.try_into().map_err(|_e| VetKdError::InvalidSeed)?;
         let rng = &mut ChaCha20Rng::from_seed(seed_32_bytes);
         use pairing::group::ff::Field;
         let secret_key = Scalar::random(rng);
@@ -67,10 +74,9 @@ impl TransportSecretKey {
         encrypted_key_bytes: &[u8],
         derived_public_key_bytes: &[u8],
         derivation_id: &[u8],
-    ) -> Result<Vec<u8>, String> {
+    ) -> Result<Vec<u8>, VetKdError> {
         let encrypted_key = EncryptedKey::deserialize(encrypted_key_bytes)?;
-        let derived_public_key = DerivedPublicKey::deserialize(derived_public_key_bytes)
-            .map_err(|e| format!("failed to deserialize public key: {:?}", e))?;
+        let derived_public_key = DerivedPublicKey::deserialize(derived_public_key_bytes)?;
         Ok(encrypted_key
             .decrypt_and_verify(self, derived_public_key, derivation_id)?
             .to_compressed()
@@ -90,7 +96,7 @@ impl TransportSecretKey {
         derivation_id: &[u8],
         symmetric_key_bytes: usize,
         symmetric_key_associated_data: &[u8],
-    ) -> Result<Vec<u8>, String> {
+    ) -> Result<Vec<u8>, VetKdError> {
         let key = self.decrypt(encrypted_key_bytes, derived_public_key_bytes, derivation_id)?;
 
         let mut ro = ro::RandomOracle::new(&format!(
@@ -108,7 +114,11 @@ impl TransportSecretKey {
     /// first hash the message to a curve point in G2
     /// then multiply the hashed message by the secret key
     /// return the serialized point
-    pub fn sign(&self, input: &[u8]) -> Result<Vec<u8>, String> {
+    ///
+    /// The message is augmented with the G1 generator instead of the public
+    /// key, which existing verifiers expect; use sign_augmented for signatures
+    /// other BLS libraries accept
+    pub fn sign(&self, input: &[u8]) -> Result<Vec<u8>, VetKdError> {
         let hashed_input = augmented_hash_to_g2(&G1Affine::generator(), input);
 
         let signed_input = hashed_input.mul(self.secret_key);
@@ -119,7 +129,7 @@ impl TransportSecretKey {
     }
 
     /// sign and hash a message with the transport secret key
-    pub fn sign_and_hash(&self, input: &[u8]) -> Result<Vec<u8>, String> {
+    pub fn sign_and_hash(&self, input: &[u8]) -> Result<Vec<u8>, VetKdError> {
         let signed_input_bytes = self.sign(input)?;
 
         let mut ro = ro::RandomOracle::new("ic-crypto-vetkd-bls12-381-sign-and-hash");
@@ -128,22 +138,49 @@ impl TransportSecretKey {
 
         Ok(hash)
     }
+
+    /// sign a message with the transport secret key under the standard BLS
+    /// message augmentation ciphersuite, see [`bls`]
+    pub fn sign_augmented(&self, input: &[u8]) -> Vec<u8> {
+        bls::SecretKey::from(self)
+            .sign(bls::Ciphersuite::MessageAugmentation, input)
+            .serialize()
+    }
 }
 #[cfg_attr(feature = "js", wasm_bindgen)]
 /// Verifies a signature on a message with the transport public key
-pub fn verify_offline(public_key: &[u8], signature: &[u8], input: &[u8]) -> Result<bool, String> {
+///
+/// Counterpart of TransportSecretKey::sign
+pub fn verify_offline(
+    public_key: &[u8],
+    signature: &[u8],
+    input: &[u8],
+) -> Result<bool, VetKdError> {
     // Deserialize the public key from G2 and signature from G1
     let public_key = deserialize_g1(public_key)?;
     let signature = deserialize_g2(signature)?;
 
     // Hash the input to a point on G2 using the public key in G1
     let hashed_input = augmented_hash_to_g2(&G1Affine::generator(), input);
-    // Pairings
-    let pairing_check_1 = pairing(&G1Affine::generator(), &signature);
-    let pairing_check_2 = pairing(&public_key, &hashed_input);
 
-    // Verify if the pairings are equal
-    Ok(pairing_check_1 == pairing_check_2)
+    // Verify e(g1, signature) == e(public_key, hashed_input)
+    Ok(bls::pairing_product_is_identity(
+        &signature,
+        &[(public_key, hashed_input)],
+    ))
+}
+
+#[cfg_attr(feature = "js", wasm_bindgen)]
+/// Verifies a signature from TransportSecretKey::sign_augmented
+pub fn verify_augmented(
+    public_key: &[u8],
+    signature: &[u8],
+    input: &[u8],
+) -> Result<bool, VetKdError> {
+    let public_key = bls::PublicKey::deserialize(public_key)?;
+    let signature = bls::Signature::deserialize(signature)?;
+
+    Ok(public_key.verify(bls::Ciphersuite::MessageAugmentation, input, &signature))
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -158,34 +195,22 @@ impl From<DerivedPublicKey> for G2Affine {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-/// Error indicating deserializing a derived public key failed
-enum DerivedPublicKeyDeserializationError {
-    /// The public key was invalid
-    InvalidPublicKey,
-}
-
 impl DerivedPublicKey {
     const BYTES: usize = G2AFFINE_BYTES;
 
     /// Deserialize a derived public key
-    fn deserialize(bytes: &[u8]) -> Result<Self, DerivedPublicKeyDeserializationError> {
-        let dpk_bytes: &[u8; Self::BYTES] = bytes.try_into().map_err(|_e: TryFromSliceError| {
-            DerivedPublicKeyDeserializationError::InvalidPublicKey
-        })?;
+    fn deserialize(bytes: &[u8]) -> Result<Self, VetKdError> {
+        let dpk_bytes: &[u8; Self::BYTES] =
+            bytes.try_into().map_err(|_e| VetKdError::InvalidLength {
+                expected: Self::BYTES,
+                actual: bytes.len(),
+            })?;
         let dpk = option_from_ctoption(G2Affine::from_compressed(dpk_bytes))
-            .ok_or(DerivedPublicKeyDeserializationError::InvalidPublicKey)?;
+            .ok_or(VetKdError::InvalidPoint)?;
         Ok(Self { point: dpk })
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-/// Error indicating that deserializing an encrypted key failed
-enum EncryptedKeyDeserializationError {
-    /// Error indicating one or more of the points was invalid
-    InvalidEncryptedKey,
-}
-
 #[derive(Clone, Debug, Eq, PartialEq)]
 /// An encrypted key
 struct EncryptedKey {
@@ -204,7 +229,7 @@ impl EncryptedKey {
         tsk: &TransportSecretKey,
         derived_public_key: DerivedPublicKey,
         derivation_id: &[u8],
-    ) -> Result<G1Affine, String> {
+    ) -> Result<G1Affine, VetKdError> {
         let k = G1Affine::from(G1Projective::from(&self.c3) - self.c1 * tsk.secret_key);
 
         let msg = augmented_hash_to_g1(&derived_public_key.point, derivation_id);
@@ -214,34 +239,34 @@ impl EncryptedKey {
         if bool::from(is_valid) {
             Ok(k)
         } else {
-            Err("invalid encrypted key: verification failed".to_string())
+            Err(VetKdError::VerificationFailed)
         }
     }
 
     /// Deserializes an encrypted key from a byte vector
-    fn deserialize(bytes: &[u8]) -> Result<EncryptedKey, String> {
-        let ek_bytes: &[u8; Self::BYTES] = bytes.try_into().map_err(|_e: TryFromSliceError| {
-            format!("key not {} bytes but {}", Self::BYTES, bytes.len())
-        })?;
-        Self::deserialize_array(ek_bytes).map_err(|e| format!("{:?}", e))
+    fn deserialize(bytes: &[u8]) -> Result<EncryptedKey, VetKdError> {
+        let ek_bytes: &[u8; Self::BYTES] =
+            bytes.try_into().map_err(|_e| VetKdError::InvalidLength {
+                expected: Self::BYTES,
+                actual: bytes.len(),
+            })?;
+        Self::deserialize_array(ek_bytes)
     }
 
     /// Deserializes an encrypted key from a byte array
-    fn deserialize_array(
-        val: &[u8; Self::BYTES],
-    ) -> Result<Self, EncryptedKeyDeserializationError> {
+    fn deserialize_array(val: &[u8; Self::BYTES]) -> Result<Self, VetKdError> {
         let c2_start = G1AFFINE_BYTES;
         let c3_start = G1AFFINE_BYTES + G2AFFINE_BYTES;
 
         let c1_bytes: &[u8; G1AFFINE_BYTES] = &val[..c2_start]
             .try_into()
-            .map_err(|_e| EncryptedKeyDeserializationError::InvalidEncryptedKey)?;
+            .map_err(|_e| VetKdError::InvalidPoint)?;
         let c2_bytes: &[u8; G2AFFINE_BYTES] = &val[c2_start..c3_start]
             .try_into()
-            .map_err(|_e| EncryptedKeyDeserializationError::InvalidEncryptedKey)?;
+            .map_err(|_e| VetKdError::InvalidPoint)?;
         let c3_bytes: &[u8; G1AFFINE_BYTES] = &val[c3_start..]
             .try_into()
-            .map_err(|_e| EncryptedKeyDeserializationError::InvalidEncryptedKey)?;
+            .map_err(|_e| VetKdError::InvalidPoint)?;
 
         let c1 = option_from_ctoption(G1Affine::from_compressed(c1_bytes));
         let c2 = option_from_ctoption(G2Affine::from_compressed(c2_bytes));
@@ -249,7 +274,7 @@ impl EncryptedKey {
 
         match (c1, c2, c3) {
             (Some(c1), Some(c2), Some(c3)) => Ok(Self { c1, c2, c3 }),
-            (_, _, _) => Err(EncryptedKeyDeserializationError::InvalidEncryptedKey),
+            (_, _, _) => Err(VetKdError::InvalidPoint),
         }
     }
 }
@@ -281,9 +306,12 @@ impl IBECiphertext {
     /// Deserialize an IBE ciphertext
     ///
     /// Returns Err if the encoding is not valid
-    pub fn deserialize(bytes: &[u8]) -> Result<IBECiphertext, String> {
+    pub fn deserialize(bytes: &[u8]) -> Result<IBECiphertext, VetKdError> {
         if bytes.len() < G2AFFINE_BYTES + IBE_SEED_BYTES {
-            return Err("IBECiphertext too short to be valid".to_string());
+            return Err(VetKdError::InvalidLength {
+                expected: G2AFFINE_BYTES + IBE_SEED_BYTES,
+                actual: bytes.len(),
+            });
         }
 
         let c1 = deserialize_g2(&bytes[0..G2AFFINE_BYTES])?;
@@ -340,13 +368,10 @@ impl IBECiphertext {
         derivation_id: &[u8],
         msg: &[u8],
         seed: &[u8],
-    ) -> Result<IBECiphertext, String> {
-        let dpk = DerivedPublicKey::deserialize(derived_public_key_bytes)
-            .map_err(|e| format!("failed to deserialize public key: {:?}", e))?;
+    ) -> Result<IBECiphertext, VetKdError> {
+        let dpk = DerivedPublicKey::deserialize(derived_public_key_bytes)?;
 
-        let seed: &[u8; IBE_SEED_BYTES] = seed
-            .try_into()
-            .map_err(|_e| format!("Provided seed must be {} bytes long ", IBE_SEED_BYTES))?;
+        let seed: &[u8; IBE_SEED_BYTES] = seed.try_into().map_err(|_e| VetKdError::InvalidSeed)?;
 
         let t = Self::hash_to_mask(seed, msg);
         let pt = augmented_hash_to_g1(&dpk.point, derivation_id);
@@ -367,7 +392,7 @@ impl IBECiphertext {
     /// IBECiphertext::encrypt).
     ///
     /// Returns the plaintext, or Err if decryption failed
-    pub fn decrypt(&self, k_bytes: &[u8]) -> Result<Vec<u8>, String> {
+    pub fn decrypt(&self, k_bytes: &[u8]) -> Result<Vec<u8>, VetKdError> {
         let k = deserialize_g1(k_bytes)?;
         let t = ic_bls12_381::pairing(&k, &self.c1);
 
@@ -382,7 +407,7 @@ impl IBECiphertext {
         if self.c1 == g_t {
             Ok(msg)
         } else {
-            Err("decryption failed".to_string())
+            Err(VetKdError::DecryptionFailed)
         }
     }
 }
@@ -427,29 +452,31 @@ fn option_from_ctoption<T>(ctoption: subtle::CtOption<T>) -> Option<T> {
     }
 }
 
-fn deserialize_g1(bytes: &[u8]) -> Result<G1Affine, String> {
-    let bytes: &[u8; G1AFFINE_BYTES] = bytes
-        .try_into()
-        .map_err(|_| "Invalid length for G1".to_string())?;
+fn deserialize_g1(bytes: &[u8]) -> Result<G1Affine, VetKdError> {
+    let bytes: &[u8; G1AFFINE_BYTES] = bytes.try_into().map_err(|_| VetKdError::InvalidLength {
+        expected: G1AFFINE_BYTES,
+        actual: bytes.len(),
+    })?;
 
     let pt = G1Affine::from_compressed(bytes);
     if bool::from(pt.is_some()) {
         Ok(pt.unwrap())
     } else {
-        Err("Invalid G1 elliptic curve point".to_string())
+        Err(VetKdError::InvalidPoint)
     }
 }
 
-fn deserialize_g2(bytes: &[u8]) -> Result<G2Affine, String> {
-    let bytes: &[u8; G2AFFINE_BYTES] = bytes
-        .try_into()
-        .map_err(|_| "Invalid length for G2".to_string())?;
+fn deserialize_g2(bytes: &[u8]) -> Result<G2Affine, VetKdError> {
+    let bytes: &[u8; G2AFFINE_BYTES] = bytes.try_into().map_err(|_| VetKdError::InvalidLength {
+        expected: G2AFFINE_BYTES,
+        actual: bytes.len(),
+    })?;
 
     let pt = G2Affine::from_compressed(bytes);
     if bool::from(pt.is_some()) {
         Ok(pt.unwrap())
     } else {
-        Err("Invalid G2 elliptic curve point".to_string())
+        Err(VetKdError::InvalidPoint)
     }
 }
 
@@ -0,0 +1,290 @@
+//! Hybrid IBE-KEM + AEAD encryption of large payloads in chunks
+//!
+//! A random data key is encrypted with IBE and the payload is encrypted under
+//! that key with AES-256-GCM-SIV in a STREAM construction, so neither side
+//! ever needs the whole payload in memory.
+//!
+//! A stream is laid out as
+//!
+//! ```text
+//! header = version (1 byte, currently 1) || chunk size (4 bytes, big endian)
+//!          || IBE ciphertext of the data key (160 bytes)
+//! stream = header || chunk_0 || ... || chunk_n
+//! ```
+//!
+//! Every chunk but the last holds exactly `chunk size` bytes of plaintext, the
+//! last holds at most `chunk size` (possibly zero). Each chunk is encrypted as
+//! `ciphertext || tag (16 bytes)` with the whole header as associated data and
+//! the nonce
+//!
+//! ```text
+//! nonce = 0 (3 bytes) || chunk index (8 bytes, big endian) || last chunk flag (1 byte)
+//! ```
+//!
+//! so chunks can not be reordered, dropped or moved between streams, and a
+//! stream cut short at a chunk boundary is detected because its last chunk
+//! never carried the flag.
+
+use aes_gcm_siv::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256GcmSiv, Nonce,
+};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::{ro, IBECiphertext, VetKdError};
+
+#[cfg(feature = "js")]
+use wasm_bindgen::prelude::*;
+
+const STREAM_VERSION: u8 = 1;
+const STREAM_KEY_BYTES: usize = 32;
+const STREAM_SEED_BYTES: usize = 32;
+const STREAM_TAG_BYTES: usize = 16;
+const STREAM_IBE_CIPHERTEXT_BYTES: usize = 96 + 32 + STREAM_KEY_BYTES;
+
+/// The length of the stream header preceding the first chunk
+pub const STREAM_HEADER_BYTES: usize = 1 + 4 + STREAM_IBE_CIPHERTEXT_BYTES;
+
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+/// The per-stream AEAD state shared by [`Encryptor`] and [`Decryptor`]
+struct ChunkCipher {
+    key: [u8; STREAM_KEY_BYTES],
+    #[zeroize(skip)]
+    header: Vec<u8>,
+    #[zeroize(skip)]
+    chunk_size: usize,
+    #[zeroize(skip)]
+    index: u64,
+    #[zeroize(skip)]
+    finished: bool,
+}
+
+impl ChunkCipher {
+    fn nonce(&self, last: bool) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[3..11].copy_from_slice(&self.index.to_be_bytes());
+        nonce[11] = last as u8;
+        nonce
+    }
+
+    fn advance(&mut self, last: bool) -> Result<[u8; 12], VetKdError> {
+        if self.finished {
+            return Err(VetKdError::StreamFinished);
+        }
+
+        let nonce = self.nonce(last);
+
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or(VetKdError::StreamFinished)?;
+        self.finished = last;
+
+        Ok(nonce)
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, VetKdError> {
+        if (last && chunk.len() > self.chunk_size) || (!last && chunk.len() != self.chunk_size) {
+            return Err(VetKdError::InvalidLength {
+                expected: self.chunk_size,
+                actual: chunk.len(),
+            });
+        }
+
+        let nonce = self.advance(last)?;
+
+        Aes256GcmSiv::new(&self.key.into())
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: chunk,
+                    aad: &self.header,
+                },
+            )
+            .map_err(|_e| VetKdError::EncryptionFailed)
+    }
+
+    fn open(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, VetKdError> {
+        let ctext_size = self.chunk_size + STREAM_TAG_BYTES;
+
+        if (last && (chunk.len() < STREAM_TAG_BYTES || chunk.len() > ctext_size))
+            || (!last && chunk.len() != ctext_size)
+        {
+            return Err(VetKdError::InvalidLength {
+                expected: ctext_size,
+                actual: chunk.len(),
+            });
+        }
+
+        if self.finished {
+            return Err(VetKdError::StreamFinished);
+        }
+
+        // Only move on once the chunk is authentic, so a bad chunk can be retried
+        let nonce = self.nonce(last);
+
+        let ptext = Aes256GcmSiv::new(&self.key.into())
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: chunk,
+                    aad: &self.header,
+                },
+            )
+            .map_err(|_e| VetKdError::DecryptionFailed)?;
+
+        self.advance(last)?;
+
+        Ok(ptext)
+    }
+}
+
+#[cfg_attr(feature = "js", wasm_bindgen(js_name = IBEStreamEncryptor))]
+/// Incrementally encrypts a payload for a derived public key and derivation id
+pub struct Encryptor {
+    cipher: ChunkCipher,
+}
+
+#[cfg_attr(feature = "js", wasm_bindgen(js_class = IBEStreamEncryptor))]
+impl Encryptor {
+    #[cfg_attr(feature = "js", wasm_bindgen(constructor))]
+    /// Starts a stream with chunks of `chunk_size` plaintext bytes
+    ///
+    /// The seed must be exactly 256 bits (32 bytes) long and should be
+    /// generated with a cryptographically secure random number generator. Both
+    /// the data key and the IBE seed are derived from it, so do not reuse it
+    /// for another stream or any other purpose.
+    pub fn new(
+        derived_public_key_bytes: &[u8],
+        derivation_id: &[u8],
+        chunk_size: u32,
+        seed: &[u8],
+    ) -> Result<Encryptor, VetKdError> {
+        if chunk_size == 0 {
+            return Err(VetKdError::InvalidChunkSize);
+        }
+
+        let seed: &[u8; STREAM_SEED_BYTES] =
+            seed.try_into().map_err(|_e| VetKdError::InvalidSeed)?;
+
+        let key = Self::derive(seed, "ic-crypto-vetkd-bls12-381-stream-data-key");
+        let ibe_seed = Self::derive(seed, "ic-crypto-vetkd-bls12-381-stream-ibe-seed");
+
+        let ibe = IBECiphertext::encrypt(
+            derived_public_key_bytes,
+            derivation_id,
+            key.as_slice(),
+            ibe_seed.as_slice(),
+        )?
+        .serialize();
+
+        let mut header = Vec::with_capacity(STREAM_HEADER_BYTES);
+        header.push(STREAM_VERSION);
+        header.extend_from_slice(&chunk_size.to_be_bytes());
+        header.extend_from_slice(&ibe);
+
+        Ok(Self {
+            cipher: ChunkCipher {
+                key: *key,
+                header,
+                chunk_size: chunk_size as usize,
+                index: 0,
+                finished: false,
+            },
+        })
+    }
+
+    /// Returns the header, which must be stored before the first chunk
+    pub fn header(&self) -> Vec<u8> {
+        self.cipher.header.clone()
+    }
+
+    /// Encrypts the next chunk, which must be exactly `chunk_size` bytes
+    pub fn encrypt_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>, VetKdError> {
+        self.cipher.seal(chunk, false)
+    }
+
+    /// Encrypts the last chunk, which may be shorter than `chunk_size` or empty
+    ///
+    /// No further chunks can be encrypted afterwards
+    pub fn finish(&mut self, chunk: &[u8]) -> Result<Vec<u8>, VetKdError> {
+        self.cipher.seal(chunk, true)
+    }
+
+    fn derive(seed: &[u8; STREAM_SEED_BYTES], domain_sep: &str) -> Zeroizing<[u8; 32]> {
+        let mut ro = ro::RandomOracle::new(domain_sep);
+        ro.update_bin(seed);
+        Zeroizing::new(ro.finalize_to_array::<32>())
+    }
+}
+
+#[cfg_attr(feature = "js", wasm_bindgen(js_name = IBEStreamDecryptor))]
+/// Incrementally decrypts a payload encrypted with [`Encryptor`]
+pub struct Decryptor {
+    cipher: ChunkCipher,
+}
+
+#[cfg_attr(feature = "js", wasm_bindgen(js_class = IBEStreamDecryptor))]
+impl Decryptor {
+    #[cfg_attr(feature = "js", wasm_bindgen(constructor))]
+    /// Opens a stream from its header
+    ///
+    /// For proper operation k_bytes should be the result of calling
+    /// TransportSecretKey::decrypt for the `derived_public_key_bytes` and
+    /// `derivation_id` the stream was encrypted for.
+    pub fn new(header: &[u8], k_bytes: &[u8]) -> Result<Decryptor, VetKdError> {
+        if header.len() != STREAM_HEADER_BYTES {
+            return Err(VetKdError::InvalidLength {
+                expected: STREAM_HEADER_BYTES,
+                actual: header.len(),
+            });
+        }
+
+        if header[0] != STREAM_VERSION {
+            return Err(VetKdError::UnsupportedVersion(header[0]));
+        }
+
+        let mut chunk_size = [0u8; 4];
+        chunk_size.copy_from_slice(&header[1..5]);
+        let chunk_size = u32::from_be_bytes(chunk_size) as usize;
+
+        if chunk_size == 0 {
+            return Err(VetKdError::InvalidChunkSize);
+        }
+
+        let data_key = Zeroizing::new(IBECiphertext::deserialize(&header[5..])?.decrypt(k_bytes)?);
+
+        let key: [u8; STREAM_KEY_BYTES] = data_key
+            .as_slice()
+            .try_into()
+            .map_err(|_e| VetKdError::DecryptionFailed)?;
+
+        Ok(Self {
+            cipher: ChunkCipher {
+                key,
+                header: header.to_vec(),
+                chunk_size,
+                index: 0,
+                finished: false,
+            },
+        })
+    }
+
+    /// Returns the length of every encrypted chunk but the last
+    pub fn encrypted_chunk_size(&self) -> usize {
+        self.cipher.chunk_size + STREAM_TAG_BYTES
+    }
+
+    /// Decrypts the next chunk, which must not be the last
+    pub fn decrypt_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>, VetKdError> {
+        self.cipher.open(chunk, false)
+    }
+
+    /// Decrypts the last chunk
+    ///
+    /// The payload is only complete and authentic once this succeeds, a stream
+    /// that ends without a valid last chunk has been truncated.
+    pub fn finish(&mut self, chunk: &[u8]) -> Result<Vec<u8>, VetKdError> {
+        self.cipher.open(chunk, true)
+    }
+}
@@ -0,0 +1,133 @@
+use aes_gcm_siv::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256GcmSiv, Nonce,
+};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::VetKdError;
+
+#[cfg(feature = "js")]
+use wasm_bindgen::prelude::*;
+
+/// The `symmetric_key_associated_data` to pass to
+/// `TransportSecretKey::decrypt_and_hash` when deriving a key for
+/// [`SymmetricNoteCipher`]
+pub const SYMMETRIC_NOTE_CIPHER_DOMAIN: &[u8] = b"aes-256-gcm-siv";
+
+const SYMMETRIC_NOTE_CIPHER_VERSION: u8 = 1;
+const SYMMETRIC_KEY_BYTES: usize = 32;
+const SYMMETRIC_NONCE_BYTES: usize = 12;
+const SYMMETRIC_TAG_BYTES: usize = 16;
+const SYMMETRIC_HEADER_BYTES: usize = 1 + SYMMETRIC_NONCE_BYTES;
+
+#[cfg_attr(feature = "js", wasm_bindgen)]
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+/// AES-256-GCM-SIV encryption of notes under a key from `decrypt_and_hash`
+///
+/// A ciphertext is laid out as
+///
+/// ```text
+/// version (1 byte, currently 1) || nonce (12 bytes) || ciphertext || tag (16 bytes)
+/// ```
+///
+/// The version byte is authenticated together with the caller's associated
+/// data, so a ciphertext can not be replayed under a different version or
+/// associated data.
+pub struct SymmetricNoteCipher {
+    key: [u8; SYMMETRIC_KEY_BYTES],
+}
+
+#[cfg_attr(feature = "js", wasm_bindgen)]
+impl SymmetricNoteCipher {
+    #[cfg_attr(feature = "js", wasm_bindgen(constructor))]
+    /// Creates a cipher from a 32-byte symmetric key
+    pub fn new(key: Vec<u8>) -> Result<SymmetricNoteCipher, VetKdError> {
+        let mut key = key;
+        let result = key
+            .as_slice()
+            .try_into()
+            .map(|key| Self { key })
+            .map_err(|_e| VetKdError::InvalidLength {
+                expected: SYMMETRIC_KEY_BYTES,
+                actual: key.len(),
+            });
+        key.zeroize();
+        result
+    }
+
+    /// Encrypts a message
+    ///
+    /// The nonce must be exactly 96 bits (12 bytes) long and should be
+    /// generated with a cryptographically secure random number generator.
+    /// GCM-SIV stays secure if a nonce is accidentally reused, but doing so
+    /// reveals whether two messages were equal.
+    pub fn encrypt(
+        &self,
+        msg: &[u8],
+        associated_data: &[u8],
+        nonce: &[u8],
+    ) -> Result<Vec<u8>, VetKdError> {
+        let nonce: &[u8; SYMMETRIC_NONCE_BYTES] =
+            nonce.try_into().map_err(|_e| VetKdError::InvalidLength {
+                expected: SYMMETRIC_NONCE_BYTES,
+                actual: nonce.len(),
+            })?;
+
+        let aad = Self::aad(SYMMETRIC_NOTE_CIPHER_VERSION, associated_data);
+
+        let ctext = self
+            .aead()
+            .encrypt(Nonce::from_slice(nonce), Payload { msg, aad: &aad })
+            .map_err(|_e| VetKdError::EncryptionFailed)?;
+
+        let mut output = Vec::with_capacity(SYMMETRIC_HEADER_BYTES + ctext.len());
+
+        output.push(SYMMETRIC_NOTE_CIPHER_VERSION);
+        output.extend_from_slice(nonce);
+        output.extend_from_slice(&ctext);
+
+        Ok(output)
+    }
+
+    /// Decrypts a ciphertext produced by [`SymmetricNoteCipher::encrypt`]
+    ///
+    /// Returns the plaintext, or Err if the ciphertext is malformed or was not
+    /// created with this key and associated data
+    pub fn decrypt(&self, ctext: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, VetKdError> {
+        if ctext.len() < SYMMETRIC_HEADER_BYTES + SYMMETRIC_TAG_BYTES {
+            return Err(VetKdError::InvalidLength {
+                expected: SYMMETRIC_HEADER_BYTES + SYMMETRIC_TAG_BYTES,
+                actual: ctext.len(),
+            });
+        }
+
+        let version = ctext[0];
+        if version != SYMMETRIC_NOTE_CIPHER_VERSION {
+            return Err(VetKdError::UnsupportedVersion(version));
+        }
+
+        let nonce = &ctext[1..SYMMETRIC_HEADER_BYTES];
+        let aad = Self::aad(version, associated_data);
+
+        self.aead()
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: &ctext[SYMMETRIC_HEADER_BYTES..],
+                    aad: &aad,
+                },
+            )
+            .map_err(|_e| VetKdError::DecryptionFailed)
+    }
+
+    fn aead(&self) -> Aes256GcmSiv {
+        Aes256GcmSiv::new(&self.key.into())
+    }
+
+    fn aad(version: u8, associated_data: &[u8]) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(1 + associated_data.len());
+        aad.push(version);
+        aad.extend_from_slice(associated_data);
+        aad
+    }
+}
@@ -103,3 +103,476 @@ fn protocol_flow_with_fixed_rng_has_expected_outputs() {
     let ptext = ctext.decrypt(&ibe_key).expect("IBE decryption failed");
     assert_eq!(ptext, msg);
 }
+
+#[test]
+fn symmetric_note_cipher_with_fixed_inputs_has_expected_outputs() {
+    let key =
+        hex::decode("e4e8c37bbc2ec30be431edea85551f5d4161da1dcd9bc76c854cc1d6a9a28a89").unwrap();
+    let cipher = SymmetricNoteCipher::new(key).unwrap();
+
+    let msg = b"is this thing on?";
+    let ad = b"note-1";
+    let nonce = hex::decode("000102030405060708090a0b").unwrap();
+
+    let ctext = cipher.encrypt(msg, ad, &nonce).unwrap();
+
+    assert_eq!(
+        hex::encode(&ctext),
+        "01000102030405060708090a0bd51cff3d25912d63a8f03f6c441d5617f831a4cfc16c5f479643804daec6326216"
+    );
+
+    let ptext = cipher
+        .decrypt(&ctext, ad)
+        .expect("Symmetric decryption failed");
+    assert_eq!(ptext, msg);
+}
+
+#[test]
+fn symmetric_note_cipher_rejects_modified_ciphertexts() {
+    let cipher = SymmetricNoteCipher::new(vec![0x42; 32]).unwrap();
+    let ctext = cipher.encrypt(b"note", b"ad", &[7u8; 12]).unwrap();
+
+    assert!(cipher.decrypt(&ctext, b"other ad").is_err());
+    assert!(cipher.decrypt(&ctext[..28], b"ad").is_err());
+
+    for i in 0..ctext.len() {
+        let mut modified = ctext.clone();
+        modified[i] ^= 1;
+        assert!(cipher.decrypt(&modified, b"ad").is_err());
+    }
+
+    let other = SymmetricNoteCipher::new(vec![0x43; 32]).unwrap();
+    assert!(other.decrypt(&ctext, b"ad").is_err());
+
+    assert!(SymmetricNoteCipher::new(vec![0x42; 16]).is_err());
+    assert!(cipher.encrypt(b"note", b"ad", &[7u8; 16]).is_err());
+}
+
+#[test]
+fn stream_encryption_with_emulated_server_side() {
+    use test_utils::*;
+
+    let seed = rand::thread_rng().gen::<u64>();
+    println!("seed {:?}", seed);
+
+    let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(seed);
+
+    let derivation_path = DerivationPath::new(b"canister-id", &[b"1", b"2"]);
+    let did = rng.gen::<[u8; 32]>();
+
+    let tsk = TransportSecretKey::from_seed(rng.gen::<[u8; 32]>().to_vec()).unwrap();
+    let tpk_bytes: [u8; 48] = tsk.public_key().try_into().unwrap();
+    let tpk = G1Affine::from_compressed(&tpk_bytes).unwrap();
+
+    let master_sk = random_scalar(&mut rng);
+    let master_pk = G2Affine::from(G2Affine::generator() * master_sk);
+
+    let derived_public_key =
+        G2Affine::from(master_pk + G2Affine::generator() * derivation_path.delta());
+    let dpk_bytes = derived_public_key.to_compressed().to_vec();
+
+    let ek = create_encrypted_key(
+        &mut rng,
+        &master_pk,
+        &master_sk,
+        &tpk,
+        &derivation_path,
+        &did,
+    );
+    let ibe_key = tsk.decrypt(&ek, &dpk_bytes, &did).unwrap();
+
+    let chunk_size = 1024;
+    let msg = (0..10 * chunk_size + 100)
+        .map(|_| rng.gen::<u8>())
+        .collect::<Vec<_>>();
+
+    let stream_seed = rng.gen::<[u8; 32]>();
+    let mut encryptor =
+        stream::Encryptor::new(&dpk_bytes, &did, chunk_size as u32, &stream_seed).unwrap();
+
+    let header = encryptor.header();
+    assert_eq!(header.len(), stream::STREAM_HEADER_BYTES);
+
+    let mut chunks = msg.chunks(chunk_size).collect::<Vec<_>>();
+    let last = chunks.pop().unwrap();
+
+    let mut ctext_chunks = chunks
+        .iter()
+        .map(|chunk| encryptor.encrypt_chunk(chunk).unwrap())
+        .collect::<Vec<_>>();
+    ctext_chunks.push(encryptor.finish(last).unwrap());
+
+    assert!(encryptor.finish(&[]).is_err());
+
+    let mut decryptor = stream::Decryptor::new(&header, &ibe_key).unwrap();
+    assert_eq!(decryptor.encrypted_chunk_size(), ctext_chunks[0].len());
+
+    // chunks can not be swapped or passed off as the last one
+    assert!(decryptor.decrypt_chunk(&ctext_chunks[1]).is_err());
+    assert!(decryptor.finish(&ctext_chunks[0]).is_err());
+
+    let mut ptext = Vec::new();
+    for chunk in &ctext_chunks[..ctext_chunks.len() - 1] {
+        ptext.extend(decryptor.decrypt_chunk(chunk).unwrap());
+    }
+    ptext.extend(decryptor.finish(ctext_chunks.last().unwrap()).unwrap());
+
+    assert_eq!(ptext, msg);
+
+    // a stream cut off at a chunk boundary does not finish
+    let mut decryptor = stream::Decryptor::new(&header, &ibe_key).unwrap();
+    decryptor.decrypt_chunk(&ctext_chunks[0]).unwrap();
+    assert!(decryptor.finish(&ctext_chunks[1]).is_err());
+
+    // the chunk size in the header can not be changed
+    let mut other_header = header.clone();
+    other_header[1..5].copy_from_slice(&(chunk_size as u32 / 2).to_be_bytes());
+    let mut decryptor = stream::Decryptor::new(&other_header, &ibe_key).unwrap();
+    assert!(decryptor.decrypt_chunk(&ctext_chunks[0]).is_err());
+}
+
+#[test]
+fn errors_can_be_told_apart() {
+    let tsk = TransportSecretKey::from_seed(vec![0x42; 32]).unwrap();
+
+    assert_eq!(
+        TransportSecretKey::from_seed(vec![0x42; 31]).err(),
+        Some(VetKdError::InvalidSeed)
+    );
+
+    let dpk_bytes = hex::decode("972c4c6cc184b56121a1d27ef1ca3a2334d1a51be93573bd18e168f78f8fe15ce44fb029ffe8e9c3ee6bea2660f4f35e0774a35a80d6236c050fd8f831475b5e145116d3e83d26c533545f64b08464e4bcc755f990a381efa89804212d4eef5f").unwrap();
+
+    let ek_bytes = hex::decode("b1a13757eaae15a3c8884fc1a3453f8a29b88984418e65f1bd21042ce1d6809b2f8a49f7326c1327f2a3921e8ff1d6c3adde2a801f1f88de98ccb40c62e366a279e7aec5875a0ce2f2a9f3e109d9cb193f0197eadb2c5f5568ee4d6a87e115910662e01e604087246be8b081fc6b8a06b4b0100ed1935d8c8d18d9f70d61718c5dba23a641487e72b3b25884eeede8feb3c71599bfbcebe60d29408795c85b4bdf19588c034d898e7fc513be8dbd04cac702a1672f5625f5833d063b05df7503").unwrap();
+
+    assert_eq!(
+        tsk.decrypt(&ek_bytes[1..], &dpk_bytes, b"message"),
+        Err(VetKdError::InvalidLength {
+            expected: 192,
+            actual: 191
+        })
+    );
+    assert_eq!(
+        tsk.decrypt(&ek_bytes, &[0xff; 96], b"message"),
+        Err(VetKdError::InvalidPoint)
+    );
+    assert_eq!(
+        tsk.decrypt(&ek_bytes, &dpk_bytes, b"another message"),
+        Err(VetKdError::VerificationFailed)
+    );
+
+    assert_eq!(
+        IBECiphertext::deserialize(&[0; 127]),
+        Err(VetKdError::InvalidLength {
+            expected: 128,
+            actual: 127
+        })
+    );
+    assert_eq!(
+        IBECiphertext::encrypt(&dpk_bytes, b"message", b"msg", &[0; 16]),
+        Err(VetKdError::InvalidSeed)
+    );
+
+    let ibe_key = tsk.decrypt(&ek_bytes, &dpk_bytes, b"message").unwrap();
+    let ctext = IBECiphertext::encrypt(&dpk_bytes, b"another message", b"msg", &[0; 32]).unwrap();
+    assert_eq!(ctext.decrypt(&ibe_key), Err(VetKdError::DecryptionFailed));
+
+    assert_eq!(
+        verify_offline(&[0xff; 48], &[0; 96], b"msg"),
+        Err(VetKdError::InvalidPoint)
+    );
+
+    let cipher = SymmetricNoteCipher::new(vec![0x42; 32]).unwrap();
+    let mut note = cipher.encrypt(b"note", b"ad", &[7u8; 12]).unwrap();
+    note[0] = 2;
+    assert_eq!(
+        cipher.decrypt(&note, b"ad"),
+        Err(VetKdError::UnsupportedVersion(2))
+    );
+}
+
+#[test]
+fn bls_signatures_match_reference_vectors() {
+    use bls::*;
+
+    let sk_bytes =
+        hex::decode("263dbd792f5b1be47ed85f8938c0f29586af0d3ac7b977f21c278fe1462040e3").unwrap();
+    let sk = SecretKey::deserialize(&sk_bytes).unwrap();
+    assert_eq!(sk.serialize(), sk_bytes);
+
+    let pk = sk.public_key();
+    assert_eq!(hex::encode(pk.serialize()),
+               "a491d1b0ecd9bb917989f0e74f0dea0422eac4a873e5e2644f368dffb9a6e20fd6e10c1b77654d067c0618f6e5a7f79a");
+
+    // from the Ethereum consensus BLS test vectors, which use the proof of
+    // possession ciphersuite
+    let sig = sk.sign(Ciphersuite::ProofOfPossession, &[0; 32]);
+    assert_eq!(hex::encode(sig.serialize()),
+               "b6ed936746e01f8ecf281f020953fbf1f01debd5657c4a383940b020b26507f6076334f91e2366c96e9ab279fb5158090352ea1c5b0c9274504f4f0e7053af24802e51e4568d164fe986834f41e55c8e850ce1f98458c0cfc9ab380b55285a55");
+    assert!(pk.verify(Ciphersuite::ProofOfPossession, &[0; 32], &sig));
+    assert!(!pk.verify(Ciphersuite::MessageAugmentation, &[0; 32], &sig));
+
+    // generated with blst
+    let proof = sk.prove_possession();
+    assert_eq!(hex::encode(proof.serialize()),
+               "b803eb0ed93ea10224a73b6b9c725796be9f5fefd215ef7a5b97234cc956cf6870db6127b7e4d824ec62276078e787db05584ce1adbf076bc0808ca0f15b73d59060254b25393d95dfc7abe3cda566842aaedf50bbb062aae1bbb6ef3b1f77e1");
+    assert!(pk.verify_possession(&proof));
+    assert!(!pk.verify_possession(&sig));
+
+    let msg = b"share link";
+    let sig = sk.sign(Ciphersuite::MessageAugmentation, msg);
+    assert_eq!(hex::encode(sig.serialize()),
+               "84187df7984ed770ac1662afabd92d6292294c3fb745303d6eb70a62fe8e764c574fd6fe6a3bcd7e58ea1631e6f16fd50010739c555b9a219584b28560f2c31e432eee086dd0148ef7ea612d54f3193dc0dc9bdd02fe693a2745f8ccc1414dda");
+    assert!(pk.verify(Ciphersuite::MessageAugmentation, msg, &sig));
+    assert!(!pk.verify(Ciphersuite::MessageAugmentation, b"another link", &sig));
+
+    let sk2 = SecretKey::deserialize(
+        &hex::decode("47b8192d77bf871b62e87859d653922725724a5c031afeabc60bcef5ff665138").unwrap(),
+    )
+    .unwrap();
+    let pk2 = sk2.public_key();
+    let sig2 = sk2.sign(Ciphersuite::MessageAugmentation, msg);
+
+    let aggregate = Signature::aggregate(&[sig, sig2]).unwrap();
+    assert_eq!(hex::encode(aggregate.serialize()),
+               "a72a871cc4d437b538e9ccc54c2c95b21c7b85e789fac995ad4b03d31e3a8a65fed96d937f03f8b87eb6de04e78d7bad01fd0c00c9b15fa454a7d1e8fd0d73d06df985711183ad1473c5b1143881425b899df680089946cf8919a5b24fa57062");
+    assert_eq!(
+        Signature::deserialize(&aggregate.serialize()).unwrap(),
+        aggregate
+    );
+
+    assert!(aggregate_verify(
+        Ciphersuite::MessageAugmentation,
+        &[pk, pk2],
+        &[msg, msg],
+        &aggregate
+    ));
+    assert!(!aggregate_verify(
+        Ciphersuite::MessageAugmentation,
+        &[pk, pk2],
+        &[msg, b"another link"],
+        &aggregate
+    ));
+    assert!(!aggregate_verify(
+        Ciphersuite::MessageAugmentation,
+        &[],
+        &[],
+        &aggregate
+    ));
+
+    let pop_aggregate = Signature::aggregate(&[
+        sk.sign(Ciphersuite::ProofOfPossession, msg),
+        sk2.sign(Ciphersuite::ProofOfPossession, msg),
+    ])
+    .unwrap();
+    assert!(fast_aggregate_verify(&[pk, pk2], msg, &pop_aggregate));
+    assert!(!fast_aggregate_verify(&[pk], msg, &pop_aggregate));
+
+    assert_eq!(
+        SecretKey::deserialize(&[0; 32]).err(),
+        Some(VetKdError::InvalidSecretKey)
+    );
+    assert_eq!(
+        PublicKey::deserialize(&G1Affine::identity().to_compressed()),
+        Err(VetKdError::InvalidPoint)
+    );
+}
+
+#[test]
+fn bls_signatures_match_published_pop_vectors() {
+    use bls::*;
+
+    // sign and fast_aggregate_verify vectors from the Ethereum consensus spec
+    // tests, which use BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_
+    let keys = [
+        (
+            "263dbd792f5b1be47ed85f8938c0f29586af0d3ac7b977f21c278fe1462040e3",
+            "a491d1b0ecd9bb917989f0e74f0dea0422eac4a873e5e2644f368dffb9a6e20fd6e10c1b77654d067c0618f6e5a7f79a",
+            [
+                "b6ed936746e01f8ecf281f020953fbf1f01debd5657c4a383940b020b26507f6076334f91e2366c96e9ab279fb5158090352ea1c5b0c9274504f4f0e7053af24802e51e4568d164fe986834f41e55c8e850ce1f98458c0cfc9ab380b55285a55",
+                "882730e5d03f6b42c3abc26d3372625034e1d871b65a8a6b900a56dae22da98abbe1b68f85e49fe7652a55ec3d0591c20767677e33e5cbb1207315c41a9ac03be39c2e7668edc043d6cb1d9fd93033caa8a1c5b0e84bedaeb6c64972503a43eb",
+                "91347bccf740d859038fcdcaf233eeceb2a436bcaaee9b2aa3bfb70efe29dfb2677562ccbea1c8e061fb9971b0753c240622fab78489ce96768259fc01360346da5b9f579e5da0d941e4c6ba18a0e64906082375394f337fa1af2b7127b0d121",
+            ],
+        ),
+        (
+            "47b8192d77bf871b62e87859d653922725724a5c031afeabc60bcef5ff665138",
+            "b301803f8b5ac4a1133581fc676dfedc60d891dd5fa99028805e5ea5b08d3491af75d0707adab3b70c6a6a580217bf81",
+            [
+                "b23c46be3a001c63ca711f87a005c200cc550b9429d5f4eb38d74322144f1b63926da3388979e5321012fb1a0526bcd100b5ef5fe72628ce4cd5e904aeaa3279527843fae5ca9ca675f4f51ed8f83bbf7155da9ecc9663100a885d5dc6df96d9",
+                "af1390c3c47acdb37131a51216da683c509fce0e954328a59f93aebda7e4ff974ba208d9a4a2a2389f892a9d418d618418dd7f7a6bc7aa0da999a9d3a5b815bc085e14fd001f6a1948768a3f4afefc8b8240dda329f984cb345c6363272ba4fe",
+                "9674e2228034527f4c083206032b020310face156d4a4685e2fcaec2f6f3665aa635d90347b6ce124eb879266b1e801d185de36a0a289b85e9039662634f2eea1e02e670bc7ab849d006a70b2f93b84597558a05b879c8d445f387a5d5b653df",
+            ],
+        ),
+        (
+            "328388aff0d4a5b7dc9205abd374e7e98f3cd9f3418edb4eafda5fb16473d216",
+            "b53d21a4cfd562c469cc81514d4ce5a6b577d8403d32a394dc265dd190b47fa9f829fdd7963afdf972e5e77854051f6f",
+            [
+                "948a7cb99f76d616c2c564ce9bf4a519f1bea6b0a624a02276443c245854219fabb8d4ce061d255af5330b078d5380681751aa7053da2c98bae898edc218c75f07e24d8802a17cd1f6833b71e58f5eb5b94208b4d0bb3848cecb075ea21be115",
+                "a4efa926610b8bd1c8330c918b7a5e9bf374e53435ef8b7ec186abf62e1b1f65aeaaeb365677ac1d1172a1f5b44b4e6d022c252c58486c0a759fbdc7de15a756acc4d343064035667a594b4c2a6f0b0b421975977f297dba63ee2f63ffe47bb6",
+                "ae82747ddeefe4fd64cf9cedb9b04ae3e8a43420cd255e3c7cd06a8d88b7c7f8638543719981c5d16fa3527c468c25f0026704a6951bde891360c7e8d12ddee0559004ccdbe6046b55bae1b257ee97f7cdb955773d7cf29adf3ccbb9975e4eb9",
+            ],
+        ),
+    ];
+    let msgs = [[0x00; 32], [0x56; 32], [0xab; 32]];
+
+    let mut public_keys = Vec::new();
+    for (sk, pk, sigs) in keys {
+        let sk = SecretKey::deserialize(&hex::decode(sk).unwrap()).unwrap();
+        let pk = PublicKey::deserialize(&hex::decode(pk).unwrap()).unwrap();
+        assert_eq!(sk.public_key(), pk);
+
+        for (msg, sig) in msgs.iter().zip(sigs) {
+            assert_eq!(
+                hex::encode(sk.sign(Ciphersuite::ProofOfPossession, msg).serialize()),
+                sig
+            );
+            let sig = Signature::deserialize(&hex::decode(sig).unwrap()).unwrap();
+            assert!(pk.verify(Ciphersuite::ProofOfPossession, msg, &sig));
+            assert!(!pk.verify(Ciphersuite::ProofOfPossession, &[0x01; 32], &sig));
+        }
+        public_keys.push(pk);
+    }
+
+    let aggregate = Signature::deserialize(
+        &hex::decode("9712c3edd73a209c742b8250759db12549b3eaf43b5ca61376d9f30e2747dbcf842d8b2ac0901d2a093713e20284a7670fcf6954e9ab93de991bb9b313e664785a075fc285806fa5224c82bde146561b446ccfc706a64b8579513cfc4ff1d930").unwrap(),
+    )
+    .unwrap();
+    assert!(fast_aggregate_verify(&public_keys, &[0xab; 32], &aggregate));
+    assert!(!fast_aggregate_verify(&public_keys, &[0x56; 32], &aggregate));
+    assert!(!fast_aggregate_verify(
+        &public_keys[..2],
+        &[0xab; 32],
+        &aggregate
+    ));
+}
+
+#[test]
+fn bls_verify_batch_rejects_a_single_invalid_signature() {
+    use bls::*;
+
+    let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(7);
+
+    let items = (0..5)
+        .map(|i| {
+            let tsk = TransportSecretKey::from_seed(rng.gen::<[u8; 32]>().to_vec()).unwrap();
+            let sk = SecretKey::from(&tsk);
+            let msg = format!("message {}", i).into_bytes();
+            let sig = sk.sign(Ciphersuite::MessageAugmentation, &msg);
+            let forged = sk.sign(Ciphersuite::MessageAugmentation, b"another message");
+            (sk.public_key(), msg, sig, forged)
+        })
+        .collect::<Vec<_>>();
+
+    for bad in 0..items.len() {
+        let batch = items
+            .iter()
+            .enumerate()
+            .map(|(i, (pk, msg, sig, forged))| {
+                (*pk, msg.as_slice(), if i == bad { *forged } else { *sig })
+            })
+            .collect::<Vec<_>>();
+
+        for (i, (pk, msg, sig)) in batch.iter().enumerate() {
+            assert_eq!(
+                pk.verify(Ciphersuite::MessageAugmentation, msg, sig),
+                i != bad
+            );
+        }
+        assert!(!verify_batch(Ciphersuite::MessageAugmentation, &batch));
+    }
+}
+
+#[test]
+fn bls_verify_batch_rejects_any_invalid_signature() {
+    use bls::*;
+
+    let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(42);
+
+    let items = (0..5)
+        .map(|i| {
+            let tsk = TransportSecretKey::from_seed(rng.gen::<[u8; 32]>().to_vec()).unwrap();
+            let sk = SecretKey::from(&tsk);
+            let msg = format!("message {}", i).into_bytes();
+            let sig = sk.sign(Ciphersuite::MessageAugmentation, &msg);
+            (sk.public_key(), msg, sig)
+        })
+        .collect::<Vec<_>>();
+
+    fn batch(items: &[(PublicKey, Vec<u8>, Signature)]) -> Vec<(PublicKey, &[u8], Signature)> {
+        items
+            .iter()
+            .map(|(pk, msg, sig)| (*pk, msg.as_slice(), *sig))
+            .collect()
+    }
+
+    assert!(verify_batch(
+        Ciphersuite::MessageAugmentation,
+        &batch(&items)
+    ));
+    assert!(verify_batch(Ciphersuite::MessageAugmentation, &[]));
+    assert!(!verify_batch(
+        Ciphersuite::ProofOfPossession,
+        &batch(&items)
+    ));
+
+    let mut swapped = items.clone();
+    swapped[0].2 = items[1].2;
+    assert!(!verify_batch(
+        Ciphersuite::MessageAugmentation,
+        &batch(&swapped)
+    ));
+
+    // two invalid signatures whose errors cancel out in a plain sum
+    let shift = G2Projective::generator();
+    let point =
+        |sig: &Signature| G2Affine::from_compressed(&sig.serialize().try_into().unwrap()).unwrap();
+    let mut cancelling = items.clone();
+    cancelling[0].2 =
+        Signature::deserialize(&G2Affine::from(point(&items[0].2) + shift).to_compressed())
+            .unwrap();
+    cancelling[1].2 =
+        Signature::deserialize(&G2Affine::from(point(&items[1].2) - shift).to_compressed())
+            .unwrap();
+
+    let aggregate = Signature::aggregate(
+        &cancelling
+            .iter()
+            .map(|(_, _, sig)| *sig)
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+    let public_keys = cancelling.iter().map(|(pk, _, _)| *pk).collect::<Vec<_>>();
+    let msgs = cancelling
+        .iter()
+        .map(|(_, msg, _)| msg.as_slice())
+        .collect::<Vec<_>>();
+    assert!(aggregate_verify(
+        Ciphersuite::MessageAugmentation,
+        &public_keys,
+        &msgs,
+        &aggregate
+    ));
+    assert!(!verify_batch(
+        Ciphersuite::MessageAugmentation,
+        &batch(&cancelling)
+    ));
+}
+
+#[test]
+fn transport_key_signatures() {
+    let tsk = TransportSecretKey::from_seed(vec![0x42; 32]).unwrap();
+    let tpk = tsk.public_key();
+    let msg = b"share link";
+
+    let legacy = tsk.sign(msg).unwrap();
+    assert_eq!(verify_offline(&tpk, &legacy, msg), Ok(true));
+    assert_eq!(verify_offline(&tpk, &legacy, b"another link"), Ok(false));
+
+    let augmented = tsk.sign_augmented(msg);
+    assert_ne!(augmented, legacy);
+    assert_eq!(verify_augmented(&tpk, &augmented, msg), Ok(true));
+    assert_eq!(verify_augmented(&tpk, &legacy, msg), Ok(false));
+    assert_eq!(
+        bls::PublicKey::deserialize(&tpk).unwrap(),
+        bls::SecretKey::from(&tsk).public_key()
+    );
+}